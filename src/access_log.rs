@@ -0,0 +1,161 @@
+use crate::access_token::AccessToken;
+use crate::auth_cookie::request_token;
+use crate::client_ip::client_ip;
+use crate::keyset::keyset_get;
+use crate::options::AccessLogConfig;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ready, Ready};
+use serde::Serialize;
+use tracing::{debug, info};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Id of the current request, it's taken from X-Request-Id header or generated
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// the incoming id is only accepted if it's short visible ascii, so it's safe in logs
+    pub fn from_headers(headers: &header::HeaderMap) -> Self {
+        let incoming = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= 128)
+            .filter(|v| v.chars().all(|c| c.is_ascii_graphic()));
+        match incoming {
+            Some(id) => RequestId(id.to_string()),
+            None => RequestId(uuid::Uuid::new_v4().simple().to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let id = request_id(req).unwrap_or_else(|| RequestId::from_headers(req.headers()).0);
+        ready(Ok(RequestId(id)))
+    }
+}
+
+/// Request id which is attached by the access log middleware
+pub fn request_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestId>().map(|v| v.0.clone())
+}
+
+/// Attach request id to the request before it's handled
+pub(crate) fn attach_request_id(req: &ServiceRequest) -> RequestId {
+    let id = RequestId::from_headers(req.headers());
+    req.extensions_mut().insert(id.clone());
+    id
+}
+
+/// Echo request id in the response
+pub(crate) fn echo_request_id<B>(rsp: &mut ServiceResponse<B>, id: &RequestId) {
+    if let Ok(value) = HeaderValue::from_str(&id.0) {
+        rsp.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessLog {
+    pub request_id: String,
+    pub client_ip: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: f32,
+    pub bytes: Option<u64>, // None if the body is streaming
+    pub user_id: Option<u64>,
+    pub user_agent: String,
+}
+
+impl AccessLog {
+    pub fn new<B: MessageBody>(rsp: &ServiceResponse<B>, id: &RequestId, latency_ms: f32) -> Self {
+        let req = rsp.request();
+        let client_ip = match client_ip(req) {
+            Some(ip) => ip.to_string(),
+            None => "-".to_string(),
+        };
+        let bytes = match rsp.response().body().size() {
+            BodySize::None => Some(0),
+            BodySize::Sized(size) => Some(size),
+            BodySize::Stream => None,
+        };
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-")
+            .to_string();
+        AccessLog {
+            request_id: id.0.clone(),
+            client_ip,
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            status: rsp.status().as_u16(),
+            latency_ms,
+            bytes,
+            user_id: token_user_id(req),
+            user_agent,
+        }
+    }
+
+    pub fn write(&self, config: &AccessLogConfig) {
+        let quiet = config.quiet_paths.iter().any(|v| v.eq(&self.path));
+        if config.json {
+            let line = serde_json::to_string(self).unwrap_or_default();
+            if quiet {
+                debug!("{}", line)
+            } else {
+                info!("{}", line)
+            }
+            return;
+        }
+        macro_rules! access_log {
+            ($level:ident) => {
+                $level!(
+                    request_id = %self.request_id,
+                    client_ip = %self.client_ip,
+                    method = %self.method,
+                    path = %self.path,
+                    status = self.status,
+                    latency_ms = self.latency_ms,
+                    bytes = ?self.bytes,
+                    user_id = ?self.user_id,
+                    user_agent = %self.user_agent,
+                    "access"
+                )
+            };
+        }
+        if quiet {
+            access_log!(debug)
+        } else {
+            access_log!(info)
+        }
+    }
+}
+
+/// The verified token is used if handler put it in request extensions, otherwise the token
+/// of header or cookie is decoded by the jwt keys
+pub(crate) fn token_user_id(req: &HttpRequest) -> Option<u64> {
+    if let Some(token) = req.extensions().get::<AccessToken>() {
+        return Some(token.user_id);
+    }
+    let keys = keyset_get()?;
+    let token = request_token(req)?;
+    AccessToken::decode_with_keys(&token, keys)
+        .ok()
+        .map(|v| v.user_id)
+}
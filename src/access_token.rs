@@ -1,24 +1,300 @@
+use crate::auth_cookie::CookieConfig;
+use crate::error::WebhttpResult;
+use crate::keyset::KeySet;
+use crate::util::now_seconds;
 use actix_http::header::HeaderMap;
 use actix_web::HttpRequest;
-use anyhow;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::Validation;
 use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::warn;
 
 #[async_trait::async_trait]
 pub trait TokenPermission {
-    async fn check_and_verify(&self, req: (HeaderMap, String)) -> anyhow::Result<AccessToken>;
+    async fn check_and_verify(&self, req: (HeaderMap, String)) -> WebhttpResult<AccessToken>;
 }
 
+#[derive(Error, Clone, Debug, PartialEq)]
+pub enum TokenError {
+    #[error("token is missing")]
+    Missing,
+    #[error("token is expired")]
+    Expired,
+    #[error("token is invalid: {reason}")]
+    Invalid { reason: String },
+    #[error("encode token with error: {reason}")]
+    Encode { reason: String },
+    #[error("token type is {actual:?}, but {expected:?} is required")]
+    WrongType {
+        expected: TokenType,
+        actual: TokenType,
+    },
+    #[error("refresh token is reused, its family is revoked")]
+    Reused,
+    #[error("token is revoked")]
+    Revoked,
+}
+
+impl From<jsonwebtoken::errors::Error> for TokenError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ExpiredSignature => TokenError::Expired,
+            _ => TokenError::Invalid {
+                reason: err.to_string(),
+            },
+        }
+    }
+}
+
+/// `[token]` of the config, see the example of WebOptions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TokenConfig {
+    /// iss of new tokens, and verified tokens must have it
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// aud of new tokens, and verified tokens must have one of them
+    #[serde(default)]
+    pub audience: Vec<String>,
+    /// seconds of clock skew which is allowed for exp and nbf
+    #[serde(default)]
+    pub leeway: u64,
+    /// tokens of claims version 1, whose exp is milliseconds, are accepted until this unix
+    /// time in seconds. None means they are always accepted. The ones which live longer
+    /// than the legacy access tokens are rejected, because they may be refresh tokens.
+    #[serde(default)]
+    pub legacy_until: Option<u64>,
+    /// seconds of access token which is issued with refresh token
+    #[serde(default = "default_access_lifetime")]
+    pub access_lifetime: u64,
+    /// seconds of refresh token, it's also the lifetime of its family
+    #[serde(default = "default_refresh_lifetime")]
+    pub refresh_lifetime: u64,
+    /// mount the refresh endpoint under api_prefix, such as auth/refresh
+    #[serde(default)]
+    pub refresh_path: Option<String>,
+    /// keep refresh token families in AppState.redis instead of process memory
+    #[serde(default)]
+    pub refresh_use_redis: bool,
+    /// mount the logout endpoint under api_prefix, such as auth/logout
+    #[serde(default)]
+    pub logout_path: Option<String>,
+    /// keep revoked tokens and users in AppState.redis, so all instances reject them
+    #[serde(default)]
+    pub revocation_use_redis: bool,
+    /// issue tokens as HttpOnly cookies besides the token header, see CookieConfig
+    #[serde(default)]
+    pub cookie: Option<CookieConfig>,
+}
+
+fn default_access_lifetime() -> u64 {
+    ACCESS_TOKEN_TIME as u64 * 60 * 60
+}
+
+fn default_refresh_lifetime() -> u64 {
+    REFRESH_TOKEN_TIME as u64 * 60 * 60
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        TokenConfig {
+            issuer: None,
+            audience: Vec::new(),
+            leeway: 0,
+            legacy_until: None,
+            access_lifetime: default_access_lifetime(),
+            refresh_lifetime: default_refresh_lifetime(),
+            refresh_path: None,
+            refresh_use_redis: false,
+            logout_path: None,
+            revocation_use_redis: false,
+            cookie: None,
+        }
+    }
+}
+
+static TOKEN_CONFIG: OnceLock<TokenConfig> = OnceLock::<TokenConfig>::new();
+
+pub fn token_init(config: TokenConfig) -> &'static TokenConfig {
+    TOKEN_CONFIG.get_or_init(|| config)
+}
+
+pub fn token_config() -> TokenConfig {
+    TOKEN_CONFIG.get().cloned().unwrap_or_default()
+}
+
+/// Version of claims, version 1 has no `ver` and its exp is milliseconds
+pub const CLAIMS_VERSION: u32 = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenType {
+    /// tokens without token_type are access tokens
+    #[default]
+    Access,
+    Refresh,
+}
+
+/// Claims of token, the custom claims in `extra` are flattened into the payload
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AccessToken {
+    #[serde(default = "legacy_version")]
+    pub ver: u32,
     pub user_id: u64,
     pub user_name: String,
     pub user_account: String,
     pub app_id: String,
-    pub exp: u128,
+    pub exp: u64, // unix time in seconds
+    #[serde(default)]
+    pub iat: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "one_or_many",
+        serialize_with = "one_or_many_serialize"
+    )]
+    pub aud: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(default)]
+    pub jti: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub token_type: TokenType,
+    /// tokens which are issued by one login and its refreshing are in the same family
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    /// unix time of the login, it's kept by refreshing and renewal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<u64>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// names of the claims of AccessToken, custom claims can't use them
+const RESERVED_CLAIMS: [&str; 17] = [
+    "ver",
+    "user_id",
+    "user_name",
+    "user_account",
+    "app_id",
+    "exp",
+    "iat",
+    "nbf",
+    "iss",
+    "aud",
+    "sub",
+    "jti",
+    "roles",
+    "scopes",
+    "token_type",
+    "family",
+    "auth_time",
+];
+
+fn legacy_version() -> u32 {
+    1
+}
+
+/// aud of RFC 7519 can be a string or an array
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+fn one_or_many_serialize<S: serde::Serializer>(
+    value: &[String],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if value.len() == 1 {
+        serializer.serialize_str(&value[0])
+    } else {
+        value.serialize(serializer)
+    }
 }
 
 impl AccessToken {
+    /// New claims with iat, exp, jti, sub and the iss/aud of TokenConfig
+    pub fn new(
+        user_id: u64,
+        user_account: &str,
+        user_name: &str,
+        app_id: &str,
+        lifetime: Duration,
+    ) -> Self {
+        let config = token_config();
+        let now = now_seconds();
+        AccessToken {
+            ver: CLAIMS_VERSION,
+            user_id,
+            user_name: user_name.to_string(),
+            user_account: user_account.to_string(),
+            app_id: app_id.to_string(),
+            exp: now.saturating_add(lifetime.as_secs()),
+            iat: now,
+            nbf: None,
+            iss: config.issuer,
+            aud: config.audience,
+            sub: Some(user_id.to_string()),
+            jti: uuid::Uuid::new_v4().simple().to_string(),
+            roles: Vec::new(),
+            scopes: Vec::new(),
+            token_type: TokenType::Access,
+            family: None,
+            auth_time: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    pub fn with_roles(mut self, roles: Vec<String>) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    pub fn with_scopes(mut self, scopes: Vec<String>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    pub fn with_not_before(mut self, nbf: u64) -> Self {
+        self.nbf = Some(nbf);
+        self
+    }
+
+    /// custom claim, the names of the standard ones are ignored with a warning
+    pub fn with_extra(mut self, key: &str, value: impl Serialize) -> Self {
+        if RESERVED_CLAIMS.contains(&key) {
+            warn!("custom claim {} is a standard claim, it's ignored", key);
+            return self;
+        }
+        if let Ok(value) = serde_json::to_value(value) {
+            self.extra.insert(key.to_string(), value);
+        }
+        self
+    }
+
+    pub fn sign(&self, keys: &KeySet) -> WebhttpResult<String> {
+        keys.encode(self)
+    }
+
+    /// HS512 token of shared secret
     pub fn encode_token(
         user_id: u64,
         user_account: &String,
@@ -26,52 +302,111 @@ impl AccessToken {
         app_id: &String,
         timeout_hour: u16,
         secret: &str,
-    ) -> anyhow::Result<String> {
-        let header = Header::new(Algorithm::HS512);
-        let my_claims = AccessToken {
-            user_id: user_id.clone(),
-            user_account: user_account.clone(),
-            user_name: user_name.clone(),
-            app_id: app_id.clone(),
-            exp: (std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                + 1000 * 60 * 60 * (timeout_hour as u128)),
-        };
-        if let Ok(token) = encode(
-            &header,
-            &my_claims,
-            &EncodingKey::from_secret(secret.as_ref()),
-        ) {
-            return anyhow::Ok(token);
-        } else {
-            return Err(anyhow::anyhow!("encode_token with error"));
+    ) -> WebhttpResult<String> {
+        let keys = KeySet::from_secret(secret);
+        Self::encode_with_keys(
+            user_id,
+            user_account,
+            user_name,
+            app_id,
+            timeout_hour,
+            &keys,
+        )
+    }
+
+    pub fn decode_token(token: &str, secret: &str) -> WebhttpResult<Self> {
+        Self::decode_with_keys(token, &KeySet::from_secret(secret))
+    }
+
+    /// Token is signed by the current key of KeySet, and has its kid
+    pub fn encode_with_keys(
+        user_id: u64,
+        user_account: &str,
+        user_name: &str,
+        app_id: &str,
+        timeout_hour: u16,
+        keys: &KeySet,
+    ) -> WebhttpResult<String> {
+        let lifetime = Duration::from_secs(60 * 60 * timeout_hour as u64);
+        Self::new(user_id, user_account, user_name, app_id, lifetime).sign(keys)
+    }
+
+    /// exp, nbf, iss and aud are verified, and legacy claims are migrated.
+    /// Refresh token is rejected, use decode_refresh_with_keys for it.
+    pub fn decode_with_keys(token: &str, keys: &KeySet) -> WebhttpResult<Self> {
+        Self::decode_typed(token, keys, TokenType::Access)
+    }
+
+    pub fn decode_refresh_with_keys(token: &str, keys: &KeySet) -> WebhttpResult<Self> {
+        Self::decode_typed(token, keys, TokenType::Refresh)
+    }
+
+    fn decode_typed(token: &str, keys: &KeySet, expected: TokenType) -> WebhttpResult<Self> {
+        let config = token_config();
+        let token = keys.decode_with::<Self>(token, |key| {
+            let mut validation = Validation::new(key.algorithm);
+            validation.leeway = config.leeway;
+            validation.validate_nbf = true;
+            validation.validate_aud = !config.audience.is_empty();
+            if !config.audience.is_empty() {
+                validation.set_audience(&config.audience);
+            }
+            if let Some(issuer) = &config.issuer {
+                validation.set_issuer(&[issuer]);
+            }
+            validation
+        })?;
+        let mut token = token.claims;
+        token.migrate(&config)?;
+        if token.token_type != expected {
+            return Err(TokenError::WrongType {
+                expected,
+                actual: token.token_type,
+            }
+            .into());
         }
+        Ok(token)
     }
 
-    pub fn decode_token(token: &String, secret: &str) -> anyhow::Result<Self> {
-        if let Ok(decode_data) = decode::<Self>(
-            &token,
-            &DecodingKey::from_secret(secret.as_ref()),
-            &Validation::new(Algorithm::HS512),
-        ) {
-            return anyhow::Ok(decode_data.claims);
-        } else {
-            return Err(anyhow::anyhow!("decode_token with error"));
+    /// Version 1 token passes the exp validation, because its exp is milliseconds
+    fn migrate(&mut self, config: &TokenConfig) -> WebhttpResult<()> {
+        if self.ver >= CLAIMS_VERSION {
+            return Ok(());
+        }
+        let now = now_seconds();
+        if config.legacy_until.is_some_and(|until| now > until) {
+            return Err(TokenError::Invalid {
+                reason: format!("claims version {} is not accepted", self.ver),
+            }
+            .into());
         }
+        self.exp /= 1000;
+        self.ver = CLAIMS_VERSION;
+        if self.is_expired() {
+            return Err(TokenError::Expired.into());
+        }
+        // refresh tokens of version 1 have no token_type, they are told by the lifetime of
+        // the legacy access tokens, access_lifetime only applies to the new tokens
+        let legacy_access = ACCESS_TOKEN_TIME as u64 * 60 * 60;
+        if self.exp > now.saturating_add(legacy_access + config.leeway) {
+            return Err(TokenError::Invalid {
+                reason: "claims version 1 lives longer than a legacy access token".into(),
+            }
+            .into());
+        }
+        Ok(())
     }
 
     pub fn is_expired(&self) -> bool {
-        let cur_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-        if self.exp < cur_time {
-            true
-        } else {
-            false
-        }
+        self.exp < now_seconds()
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|v| v.eq(role))
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|v| v.eq(scope))
     }
 }
 
@@ -84,7 +419,7 @@ pub fn create_access_token(
     user_name: &String,
     app_id: &String,
     secret: &str,
-) -> anyhow::Result<String> {
+) -> WebhttpResult<String> {
     AccessToken::encode_token(
         user_id,
         user_account,
@@ -95,21 +430,21 @@ pub fn create_access_token(
     )
 }
 
+/// Refresh token of a new family, its jti is also the family, and the family is started in
+/// the refresh store by its first refreshing. refresh::issue_token_pair starts the family
+/// at once, and it's preferred when the refresh store is initialized.
 pub fn create_refresh_token(
     user_id: u64,
-    user_account: &String,
-    user_name: &String,
-    app_id: &String,
+    user_account: &str,
+    user_name: &str,
+    app_id: &str,
     secret: &str,
-) -> anyhow::Result<String> {
-    AccessToken::encode_token(
-        user_id,
-        user_account,
-        user_name,
-        app_id,
-        REFRESH_TOKEN_TIME,
-        secret,
-    )
+) -> WebhttpResult<String> {
+    let lifetime = Duration::from_secs(default_refresh_lifetime());
+    let mut token = AccessToken::new(user_id, user_account, user_name, app_id, lifetime);
+    token.token_type = TokenType::Refresh;
+    token.family = Some(token.jti.clone());
+    token.sign(&KeySet::from_secret(secret))
 }
 
 pub fn get_token_and_path(req: &HttpRequest) -> (HeaderMap, String) {
@@ -117,3 +452,76 @@ pub fn get_token_and_path(req: &HttpRequest) -> (HeaderMap, String) {
     let reqpath = format!("{} {}", req.method(), req.path());
     (headers.clone(), reqpath)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy_token(hours: u64, keys: &KeySet) -> String {
+        let exp = (now_seconds() + hours * 60 * 60) * 1000;
+        let claims = serde_json::json!({
+            "user_id": 1,
+            "user_name": "name",
+            "user_account": "account",
+            "app_id": "app",
+            "exp": exp,
+        });
+        keys.encode(&claims).unwrap()
+    }
+
+    #[test]
+    fn reserved_extra_claims_are_ignored() {
+        let keys = KeySet::from_secret("secret");
+        let token = AccessToken::new(1, "account", "name", "app", Duration::from_secs(60))
+            .with_extra("exp", 1)
+            .with_extra("sub", "other")
+            .with_extra("tenant", "acme");
+        assert_eq!(token.extra.len(), 1);
+        let decoded = AccessToken::decode_with_keys(&token.sign(&keys).unwrap(), &keys).unwrap();
+        assert_eq!(decoded.exp, token.exp);
+        assert_eq!(decoded.sub.as_deref(), Some("1"));
+        assert_eq!(decoded.extra["tenant"], "acme");
+    }
+
+    #[test]
+    fn legacy_access_token_is_migrated() {
+        let keys = KeySet::from_secret("secret");
+        let token = legacy_token(ACCESS_TOKEN_TIME as u64, &keys);
+        let token = AccessToken::decode_with_keys(&token, &keys).unwrap();
+        assert_eq!(token.ver, CLAIMS_VERSION);
+        assert_eq!(token.token_type, TokenType::Access);
+    }
+
+    #[test]
+    fn legacy_access_token_outlives_short_access_lifetime() {
+        let keys = KeySet::from_secret("secret");
+        let config = TokenConfig {
+            access_lifetime: 600,
+            ..Default::default()
+        };
+        let token = legacy_token(ACCESS_TOKEN_TIME as u64, &keys);
+        let mut token: AccessToken = keys.decode(&token).unwrap();
+        assert!(token.migrate(&config).is_ok());
+
+        let token = legacy_token(REFRESH_TOKEN_TIME as u64, &keys);
+        let mut token: AccessToken = keys.decode(&token).unwrap();
+        assert!(token.migrate(&config).is_err());
+    }
+
+    #[test]
+    fn legacy_refresh_token_is_rejected() {
+        let keys = KeySet::from_secret("secret");
+        let token = legacy_token(REFRESH_TOKEN_TIME as u64, &keys);
+        assert!(AccessToken::decode_with_keys(&token, &keys).is_err());
+        assert!(AccessToken::decode_refresh_with_keys(&token, &keys).is_err());
+    }
+
+    #[test]
+    fn created_refresh_token_has_family() {
+        let token = create_refresh_token(1, "account", "name", "app", "secret").unwrap();
+        let keys = KeySet::from_secret("secret");
+        assert!(AccessToken::decode_with_keys(&token, &keys).is_err());
+        let token = AccessToken::decode_refresh_with_keys(&token, &keys).unwrap();
+        assert_eq!(token.family.as_deref(), Some(token.jti.as_str()));
+    }
+}
@@ -0,0 +1,389 @@
+use crate::access_token::{token_config, AccessToken, TokenError, TokenPermission};
+use crate::error::WebhttpResult;
+use crate::revocation::revocation_get;
+use crate::util::{now_seconds, split_list};
+use actix_http::header::HeaderMap;
+use dashmap::DashMap;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, FromQueryResult, Statement};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+const API_KEY_PREFIX: &str = "whk_";
+
+/// last_used_at is written at most once in this interval for every key
+const TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Key of machine client, only the sha256 hash of key is stored
+///
+/// Example
+///
+/// ```toml
+/// [[api_keys]]
+/// id = "billing-cron"
+/// name = "billing cron job"
+/// hash = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+/// user_id = 10001
+/// scopes = ["order:read", "invoice:write"]
+/// expires_at = 1767225600
+/// ```
+///
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ApiKeyConfig {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    /// hex of sha256, it's given by ApiKeyAuth::generate or ApiKeyAuth::hash
+    pub hash: String,
+    /// user of the principal, the owner of key or a service account
+    #[serde(default)]
+    pub user_id: u64,
+    #[serde(default)]
+    pub app_id: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// unix time in seconds, None means the key never expires
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+/// Storage of api keys, which is looked up by the hash of key
+#[async_trait::async_trait]
+pub trait ApiKeyStore {
+    async fn find_by_hash(&self, hash: &str) -> WebhttpResult<Option<ApiKeyConfig>>;
+    /// record the last used time of key in seconds
+    async fn touch(&self, id: &str, used_at: u64) -> WebhttpResult<()>;
+}
+
+/// Keys of config, last used time is only kept in memory
+pub struct ConfigApiKeyStore {
+    keys: Vec<ApiKeyConfig>,
+    last_used: DashMap<String, u64>,
+}
+
+impl ConfigApiKeyStore {
+    pub fn new(keys: Vec<ApiKeyConfig>) -> Self {
+        ConfigApiKeyStore {
+            keys,
+            last_used: DashMap::new(),
+        }
+    }
+
+    pub fn last_used_at(&self, id: &str) -> Option<u64> {
+        self.last_used.get(id).map(|v| *v)
+    }
+}
+
+#[async_trait::async_trait]
+impl ApiKeyStore for ConfigApiKeyStore {
+    async fn find_by_hash(&self, hash: &str) -> WebhttpResult<Option<ApiKeyConfig>> {
+        Ok(self
+            .keys
+            .iter()
+            .find(|v| v.hash.eq_ignore_ascii_case(hash))
+            .cloned())
+    }
+
+    async fn touch(&self, id: &str, used_at: u64) -> WebhttpResult<()> {
+        self.last_used.insert(id.to_string(), used_at);
+        Ok(())
+    }
+}
+
+/// Keys of mysql table, roles and scopes are separated by comma
+///
+/// ```sql
+/// CREATE TABLE api_keys (
+///     id VARCHAR(64) PRIMARY KEY,
+///     name VARCHAR(128) NOT NULL DEFAULT '',
+///     hash CHAR(64) NOT NULL UNIQUE,
+///     user_id BIGINT UNSIGNED NOT NULL DEFAULT 0,
+///     app_id VARCHAR(64) NOT NULL DEFAULT '',
+///     roles VARCHAR(1024) NOT NULL DEFAULT '',
+///     scopes VARCHAR(1024) NOT NULL DEFAULT '',
+///     expires_at BIGINT UNSIGNED NULL,
+///     disabled BOOLEAN NOT NULL DEFAULT FALSE,
+///     last_used_at BIGINT UNSIGNED NULL
+/// );
+/// ```
+///
+pub struct DbApiKeyStore {
+    db: DatabaseConnection,
+    table: String,
+}
+
+#[derive(Debug, FromQueryResult)]
+struct ApiKeyRow {
+    id: String,
+    name: String,
+    hash: String,
+    user_id: u64,
+    app_id: String,
+    roles: String,
+    scopes: String,
+    expires_at: Option<u64>,
+    disabled: bool,
+}
+
+impl DbApiKeyStore {
+    pub fn new(db: DatabaseConnection) -> Self {
+        DbApiKeyStore {
+            db,
+            table: "api_keys".into(),
+        }
+    }
+
+    pub fn with_table(mut self, table: &str) -> Self {
+        self.table = table.to_string();
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl ApiKeyStore for DbApiKeyStore {
+    async fn find_by_hash(&self, hash: &str) -> WebhttpResult<Option<ApiKeyConfig>> {
+        let sql = format!(
+            "SELECT id, name, hash, user_id, app_id, roles, scopes, expires_at, disabled \
+             FROM {} WHERE hash = ?",
+            self.table
+        );
+        let row = ApiKeyRow::find_by_statement(Statement::from_sql_and_values(
+            DbBackend::MySql,
+            sql,
+            [hash.to_lowercase().into()],
+        ))
+        .one(&self.db)
+        .await?;
+        Ok(row.map(|row| ApiKeyConfig {
+            id: row.id,
+            name: row.name,
+            hash: row.hash,
+            user_id: row.user_id,
+            app_id: row.app_id,
+            roles: split_list(&row.roles),
+            scopes: split_list(&row.scopes),
+            expires_at: row.expires_at,
+            disabled: row.disabled,
+        }))
+    }
+
+    async fn touch(&self, id: &str, used_at: u64) -> WebhttpResult<()> {
+        let sql = format!("UPDATE {} SET last_used_at = ? WHERE id = ?", self.table);
+        self.db
+            .execute(Statement::from_sql_and_values(
+                DbBackend::MySql,
+                sql,
+                [used_at.into(), id.into()],
+            ))
+            .await?;
+        Ok(())
+    }
+}
+
+/// TokenPermission of api keys in the `x-api-key` header
+///
+/// The principal is an AccessToken whose jti is the key id, and user_account is
+/// `apikey:{id}`, so the same permission checks are applied. Its exp is at most
+/// access_lifetime later, and it isn't revoked by RevocationStore::revoke_user because it's
+/// issued on every request, disable the key or revoke its id by revoke_token instead.
+/// Requests without api key are checked by the fallback, such as the user tokens of browser.
+pub struct ApiKeyAuth {
+    store: Arc<dyn ApiKeyStore + Send + Sync>,
+    header: String,
+    fallback: Option<Arc<dyn TokenPermission + Send + Sync>>,
+    touched: DashMap<String, Instant>,
+}
+
+impl ApiKeyAuth {
+    pub fn new(store: Arc<dyn ApiKeyStore + Send + Sync>) -> Self {
+        ApiKeyAuth {
+            store,
+            header: API_KEY_HEADER.into(),
+            fallback: None,
+            touched: DashMap::new(),
+        }
+    }
+
+    pub fn with_header(mut self, header: &str) -> Self {
+        self.header = header.to_lowercase();
+        self
+    }
+
+    pub fn with_fallback(mut self, fallback: Arc<dyn TokenPermission + Send + Sync>) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// hex of sha256, the keys have enough entropy, so a slow hash is not needed
+    pub fn hash(key: &str) -> String {
+        Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|v| format!("{:02x}", v))
+            .collect()
+    }
+
+    /// New random key and its hash, the key is only shown to its owner once
+    pub fn generate() -> (String, String) {
+        let key = format!(
+            "{}{}{}",
+            API_KEY_PREFIX,
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let hash = Self::hash(&key);
+        (key, hash)
+    }
+
+    pub async fn verify(&self, key: &str) -> WebhttpResult<AccessToken> {
+        let record =
+            self.store
+                .find_by_hash(&Self::hash(key))
+                .await?
+                .ok_or(TokenError::Invalid {
+                    reason: "api key is unknown".into(),
+                })?;
+        if record.disabled {
+            return Err(TokenError::Revoked.into());
+        }
+        let now = now_seconds();
+        if record.expires_at.is_some_and(|v| v <= now) {
+            return Err(TokenError::Expired.into());
+        }
+        let principal = Self::principal(record, now, token_config().access_lifetime);
+        if let Some(store) = revocation_get() {
+            store.check(&principal).await?;
+        }
+        self.touch(&principal.jti, now).await;
+        Ok(principal)
+    }
+
+    async fn touch(&self, id: &str, now: u64) {
+        let due = self
+            .touched
+            .get(id)
+            .is_none_or(|v| v.elapsed() >= TOUCH_INTERVAL);
+        if !due {
+            return;
+        }
+        self.touched.insert(id.to_string(), Instant::now());
+        if let Err(e) = self.store.touch(id, now).await {
+            warn!(
+                "record last used time of api key {} with error: {:?}",
+                id, e
+            );
+        }
+    }
+
+    /// exp of principal is the earlier one of the key and access_lifetime
+    fn principal(record: ApiKeyConfig, now: u64, access_lifetime: u64) -> AccessToken {
+        let account = format!("apikey:{}", record.id);
+        let exp = std::cmp::min(
+            record.expires_at.unwrap_or(u64::MAX),
+            now.saturating_add(access_lifetime),
+        );
+        let lifetime = Duration::from_secs(exp.saturating_sub(now));
+        let mut token = AccessToken::new(
+            record.user_id,
+            &account,
+            &record.name,
+            &record.app_id,
+            lifetime,
+        )
+        .with_roles(record.roles)
+        .with_scopes(record.scopes)
+        .with_extra("api_key_id", &record.id);
+        token.jti = record.id;
+        token.iat = now;
+        token.exp = exp;
+        token
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenPermission for ApiKeyAuth {
+    async fn check_and_verify(&self, req: (HeaderMap, String)) -> WebhttpResult<AccessToken> {
+        let key = req
+            .0
+            .get(self.header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        match (key, &self.fallback) {
+            (Some(key), _) => {
+                debug!("verify api key of {}", req.1);
+                self.verify(&key).await
+            }
+            (None, Some(fallback)) => fallback.check_and_verify(req).await,
+            (None, None) => Err(TokenError::Missing.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn auth(record: ApiKeyConfig) -> ApiKeyAuth {
+        ApiKeyAuth::new(Arc::new(ConfigApiKeyStore::new(vec![record])))
+    }
+
+    fn record(hash: &str) -> ApiKeyConfig {
+        ApiKeyConfig {
+            id: "cron".into(),
+            hash: hash.to_string(),
+            user_id: 10001,
+            scopes: vec!["order:read".into()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn generated_key_is_verified() {
+        let (key, hash) = ApiKeyAuth::generate();
+        assert!(key.starts_with(API_KEY_PREFIX));
+        assert_eq!(ApiKeyAuth::hash(&key), hash);
+        let token = block_on(auth(record(&hash)).verify(&key)).unwrap();
+        assert_eq!(token.jti, "cron");
+        assert_eq!(token.user_account, "apikey:cron");
+        assert!(token.has_scope("order:read"));
+        assert!(block_on(auth(record(&hash)).verify("whk_other")).is_err());
+    }
+
+    #[test]
+    fn principal_exp_is_bounded() {
+        let now = 1_700_000_000;
+        let token = ApiKeyAuth::principal(record(""), now, 7200);
+        assert_eq!(token.iat, now);
+        assert_eq!(token.exp, now + 7200);
+
+        let mut expiring = record("");
+        expiring.expires_at = Some(now + 60);
+        let token = ApiKeyAuth::principal(expiring, now, 7200);
+        assert_eq!(token.iat, now);
+        assert_eq!(token.exp, std::cmp::min(now + 60, now + 7200));
+
+        let mut lasting = record("");
+        lasting.expires_at = Some(now + 86400);
+        let token = ApiKeyAuth::principal(lasting, now, 7200);
+        assert_eq!(token.exp, std::cmp::min(now + 86400, now + 7200));
+    }
+
+    #[test]
+    fn disabled_and_expired_keys_are_rejected() {
+        let (key, hash) = ApiKeyAuth::generate();
+        let mut disabled = record(&hash);
+        disabled.disabled = true;
+        assert!(block_on(auth(disabled).verify(&key)).is_err());
+
+        let mut expired = record(&hash);
+        expired.expires_at = Some(now_seconds() - 1);
+        assert!(block_on(auth(expired).verify(&key)).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::access_token::{get_token_and_path, AccessToken, TokenError, TokenPermission};
+use crate::auth_cookie::{clear_token_cookies, with_cookie_token};
+use crate::error::{WebhttpError, WebhttpResult};
+use crate::keyset::keyset_get;
+use crate::response::{NoneBodyData, Response};
+use crate::revocation::revocation_get;
+use crate::AppState;
+use actix_http::header::HeaderMap;
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use std::sync::Arc;
+
+/// Verify the token by AppState.token_check, or by the jwt keys if it's not given, and
+/// reject the revoked one. AppState.token_check already rejects the revoked tokens, other
+/// checks should be wrapped by RevocationCheck.
+pub async fn verify_token(
+    token_check: Option<Arc<dyn TokenPermission + Send + Sync>>,
+    req: (HeaderMap, String),
+) -> WebhttpResult<AccessToken> {
+    if let Some(token_check) = token_check {
+        return token_check.check_and_verify(req).await;
+    }
+    let keys =
+        keyset_get().ok_or_else(|| WebhttpError::Config("jwt keys are not configured".into()))?;
+    let token = req
+        .0
+        .get("token")
+        .and_then(|v| v.to_str().ok())
+        .ok_or(TokenError::Missing)?;
+    let token = AccessToken::decode_with_keys(token, keys)?;
+    if let Some(store) = revocation_get() {
+        store.check(&token).await?;
+    }
+    Ok(token)
+}
+
+/// Same as get_token_and_path, and the token of cookie is in the "token" header
+pub fn token_and_path(req: &HttpRequest) -> (HeaderMap, String) {
+    let (mut headers, path) = get_token_and_path(req);
+    with_cookie_token(req, &mut headers);
+    (headers, path)
+}
+
+/// Verify the token of request once, the token is kept in request extensions
+pub async fn verify_request(req: &HttpRequest) -> WebhttpResult<AccessToken> {
+    if let Some(token) = req.extensions().get::<AccessToken>() {
+        return Ok(token.clone());
+    }
+    let token_check = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|v| v.token_check.clone());
+    let token = verify_token(token_check, token_and_path(req)).await?;
+    req.extensions_mut().insert(token.clone());
+    Ok(token)
+}
+
+/// Handlers get the verified token by the AccessToken argument
+impl FromRequest for AccessToken {
+    type Error = WebhttpError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { verify_request(&req).await })
+    }
+}
+
+/// Mounted under api_prefix when `token.logout_path` is configured, the token and its
+/// refresh family are revoked
+pub async fn logout_handler(req: HttpRequest, token: AccessToken) -> HttpResponse {
+    let result = match revocation_get() {
+        Some(store) => store.logout(&token).await,
+        None => Err(WebhttpError::Config(
+            "revocation store is not initialized".into(),
+        )),
+    };
+    match result {
+        Ok(_) => {
+            let mut rsp = Response::<NoneBodyData>::no_content().finished_for(&req);
+            clear_token_cookies(&mut rsp);
+            rsp
+        }
+        Err(e) => Response::<NoneBodyData>::from(e).finished_for(&req),
+    }
+}
@@ -0,0 +1,481 @@
+use crate::access_token::{token_config, AccessToken, TokenConfig, CLAIMS_VERSION};
+use crate::keyset::keyset_get;
+use crate::refresh::TokenPair;
+use crate::response::{NoneBodyData, Response};
+use crate::util::now_seconds;
+use actix_http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::sync::OnceLock;
+use tracing::{debug, warn};
+
+/// `[token.cookie]` of the config, see the example of WebOptions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CookieConfig {
+    /// HttpOnly cookie of access token
+    pub name: String,
+    /// HttpOnly cookie of refresh token
+    pub refresh_name: String,
+    pub domain: Option<String>,
+    pub path: String,
+    /// refresh token is only sent to this path, default is the refresh endpoint of
+    /// `token.refresh_path`, or `path` if there is no refresh endpoint
+    pub refresh_path: Option<String>,
+    pub secure: bool,
+    pub same_site: SameSitePolicy,
+    pub csrf: CsrfMode,
+    /// readable cookie of csrf token, which is sent back in `csrf_header`
+    pub csrf_cookie: String,
+    pub csrf_header: String,
+    /// origins which are trusted by origin check besides the same origin, only the same
+    /// origin is trusted if it's empty
+    pub trusted_origins: Vec<String>,
+    /// seconds, a new access token cookie is issued when the token of an authenticated
+    /// request expires in this time. 0 means no renewal. Only the tokens of a login are
+    /// renewed, and not after refresh_lifetime since the login, then the refresh endpoint
+    /// has to be used.
+    pub renew_before: u64,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            name: "access_token".into(),
+            refresh_name: "refresh_token".into(),
+            domain: None,
+            path: "/".into(),
+            refresh_path: None,
+            secure: true,
+            same_site: SameSitePolicy::Lax,
+            csrf: CsrfMode::DoubleSubmit,
+            csrf_cookie: "csrf_token".into(),
+            csrf_header: "x-csrf-token".into(),
+            trusted_origins: Vec::new(),
+            renew_before: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SameSitePolicy {
+    Strict,
+    Lax,
+    None,
+}
+
+/// Protection of state-changing requests which are authenticated by cookie
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CsrfMode {
+    /// csrf header must be same as the csrf cookie
+    DoubleSubmit,
+    /// Origin or Referer must be same origin or trusted
+    Origin,
+    /// only for SameSite=Strict cookies
+    Disabled,
+}
+
+static COOKIE_CONFIG: OnceLock<Option<CookieConfig>> = OnceLock::<Option<CookieConfig>>::new();
+
+/// `refresh_endpoint` is the full path of the refresh endpoint, it's the default
+/// refresh_path of cookie
+pub fn cookie_init(
+    config: Option<CookieConfig>,
+    refresh_endpoint: Option<String>,
+) -> Option<&'static CookieConfig> {
+    COOKIE_CONFIG
+        .get_or_init(|| {
+            config.map(|mut config| {
+                if config.refresh_path.is_none() {
+                    config.refresh_path = refresh_endpoint;
+                }
+                config
+            })
+        })
+        .as_ref()
+}
+
+/// return None when cookie authentication is not enabled
+pub fn cookie_config() -> Option<&'static CookieConfig> {
+    COOKIE_CONFIG.get().and_then(|v| v.as_ref())
+}
+
+impl CookieConfig {
+    fn build(&self, name: &str, value: String, path: &str, max_age: u64) -> Cookie<'static> {
+        let mut cookie = Cookie::build(name.to_string(), value)
+            .path(path.to_string())
+            .secure(self.secure)
+            .same_site(match self.same_site {
+                SameSitePolicy::Strict => SameSite::Strict,
+                SameSitePolicy::Lax => SameSite::Lax,
+                SameSitePolicy::None => SameSite::None,
+            })
+            .max_age(time::Duration::seconds(max_age as i64))
+            .finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+
+    fn refresh_path(&self) -> &str {
+        self.refresh_path.as_deref().unwrap_or(&self.path)
+    }
+
+    pub fn access_cookie(&self, token: &str, max_age: u64) -> Cookie<'static> {
+        let mut cookie = self.build(&self.name, token.to_string(), &self.path, max_age);
+        cookie.set_http_only(true);
+        cookie
+    }
+
+    pub fn refresh_cookie(&self, token: &str, max_age: u64) -> Cookie<'static> {
+        let mut cookie = self.build(
+            &self.refresh_name,
+            token.to_string(),
+            self.refresh_path(),
+            max_age,
+        );
+        cookie.set_http_only(true);
+        cookie
+    }
+
+    /// readable by javascript, so it can be sent back in the csrf header
+    pub fn csrf_cookie(&self, max_age: u64) -> Cookie<'static> {
+        let value = uuid::Uuid::new_v4().simple().to_string();
+        self.build(&self.csrf_cookie, value, &self.path, max_age)
+    }
+}
+
+/// Session of cookie authentication, the tokens are only in the cookies
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CookieSession {
+    pub expires_in: u64,
+    pub refresh_expires_in: u64,
+}
+
+impl From<&TokenPair> for CookieSession {
+    fn from(pair: &TokenPair) -> Self {
+        CookieSession {
+            expires_in: pair.expires_in,
+            refresh_expires_in: pair.refresh_expires_in,
+        }
+    }
+}
+
+/// Set the cookies of access token, refresh token and csrf token
+pub fn set_token_cookies(rsp: &mut HttpResponse, pair: &TokenPair) {
+    let config = match cookie_config() {
+        Some(config) => config,
+        None => return,
+    };
+    let cookies = [
+        config.access_cookie(&pair.access_token, pair.expires_in),
+        config.refresh_cookie(&pair.refresh_token, pair.refresh_expires_in),
+        config.csrf_cookie(pair.refresh_expires_in),
+    ];
+    for cookie in cookies.iter() {
+        if let Err(e) = rsp.add_cookie(cookie) {
+            warn!("set cookie {} with error: {:?}", cookie.name(), e);
+        }
+    }
+}
+
+/// Expire the cookies, such as logout
+pub fn clear_token_cookies(rsp: &mut HttpResponse) {
+    let config = match cookie_config() {
+        Some(config) => config,
+        None => return,
+    };
+    let cookies = [
+        config.build(&config.name, String::new(), &config.path, 0),
+        config.build(
+            &config.refresh_name,
+            String::new(),
+            config.refresh_path(),
+            0,
+        ),
+        config.build(&config.csrf_cookie, String::new(), &config.path, 0),
+    ];
+    for cookie in cookies.iter() {
+        let _ = rsp.add_cookie(cookie);
+    }
+}
+
+/// Access token of the "token" header, or the cookie if cookie authentication is enabled
+pub fn request_token(req: &HttpRequest) -> Option<String> {
+    if let Some(token) = req.headers().get("token").and_then(|v| v.to_str().ok()) {
+        return Some(token.to_string());
+    }
+    let config = cookie_config()?;
+    req.cookie(&config.name).map(|v| v.value().to_string())
+}
+
+pub fn request_refresh_token(req: &HttpRequest) -> Option<String> {
+    let config = cookie_config()?;
+    req.cookie(&config.refresh_name)
+        .map(|v| v.value().to_string())
+}
+
+/// The token of cookie is put in the "token" header, so every TokenPermission reads it by
+/// the same way
+pub fn with_cookie_token(req: &HttpRequest, headers: &mut HeaderMap) {
+    if headers.contains_key("token") {
+        return;
+    }
+    if let Some(token) = request_token(req) {
+        if let Ok(value) = HeaderValue::from_str(&token) {
+            headers.insert(HeaderName::from_static("token"), value);
+        }
+    }
+}
+
+/// Request is authenticated by cookie if it has no token header but has the token cookies
+fn cookie_authenticated(req: &HttpRequest, config: &CookieConfig) -> bool {
+    !req.headers().contains_key("token")
+        && (req.cookie(&config.name).is_some() || req.cookie(&config.refresh_name).is_some())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn csrf_passed(req: &HttpRequest, config: &CookieConfig) -> bool {
+    match config.csrf {
+        CsrfMode::Disabled => true,
+        CsrfMode::DoubleSubmit => {
+            let cookie = match req.cookie(&config.csrf_cookie) {
+                Some(cookie) => cookie,
+                None => return false,
+            };
+            req.headers()
+                .get(config.csrf_header.as_str())
+                .is_some_and(|v| constant_time_eq(v.as_bytes(), cookie.value().as_bytes()))
+        }
+        CsrfMode::Origin => {
+            let origin = req
+                .headers()
+                .get(header::ORIGIN)
+                .or_else(|| req.headers().get(header::REFERER))
+                .and_then(|v| v.to_str().ok())
+                .map(|v| match v.splitn(4, '/').collect::<Vec<_>>()[..] {
+                    // scheme://host of referer
+                    [scheme, "", host, ..] => format!("{}//{}", scheme, host),
+                    _ => v.to_string(),
+                });
+            let origin = match origin {
+                Some(origin) => origin,
+                None => return false,
+            };
+            let info = req.connection_info();
+            if origin.eq_ignore_ascii_case(&format!("{}://{}", info.scheme(), info.host())) {
+                return true;
+            }
+            config
+                .trusted_origins
+                .iter()
+                .any(|v| v.eq_ignore_ascii_case(&origin))
+        }
+    }
+}
+
+/// The renewed claims of the token which will expire soon, None if it isn't renewed. The
+/// token must be issued with a refresh family, and it's not renewed after refresh_lifetime
+/// since the login, so the renewal can't keep a session longer than the refresh token.
+fn renewed_token(
+    token: &AccessToken,
+    renew_before: u64,
+    config: &TokenConfig,
+    now: u64,
+) -> Option<AccessToken> {
+    let auth_time = token.auth_time?;
+    token.family.as_ref()?;
+    if token.exp > now.saturating_add(renew_before) {
+        return None;
+    }
+    let exp = std::cmp::min(
+        now.saturating_add(config.access_lifetime),
+        auth_time.saturating_add(config.refresh_lifetime),
+    );
+    if exp <= std::cmp::max(token.exp, now) {
+        return None;
+    }
+    let mut renewed = token.clone();
+    renewed.ver = CLAIMS_VERSION;
+    renewed.jti = uuid::Uuid::new_v4().simple().to_string();
+    renewed.iat = now;
+    renewed.nbf = None;
+    renewed.exp = exp;
+    Some(renewed)
+}
+
+/// Access token which is verified by the handler and will expire soon is renewed
+fn renewed_cookie(req: &HttpRequest, config: &CookieConfig) -> Option<Cookie<'static>> {
+    if config.renew_before == 0 || req.cookie(&config.name).is_none() {
+        return None;
+    }
+    let token = req.extensions().get::<AccessToken>().cloned()?;
+    let now = now_seconds();
+    let renewed = renewed_token(&token, config.renew_before, &token_config(), now)?;
+    match renewed.sign(keyset_get()?) {
+        Ok(token) => {
+            debug!("renew access token cookie of user {}", renewed.user_id);
+            Some(config.access_cookie(&token, renewed.exp.saturating_sub(now)))
+        }
+        Err(e) => {
+            warn!("renew access token with error: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Csrf check and token renewal of cookie authentication, it does nothing if
+/// `token.cookie` is not configured
+pub struct CookieAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for CookieAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = CookieAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CookieAuthMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CookieAuthMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CookieAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let config = match cookie_config() {
+                Some(config) if cookie_authenticated(req.request(), config) => config,
+                _ => return Ok(service.call(req).await?.map_into_left_body()),
+            };
+            let safe = matches!(
+                *req.method(),
+                Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+            );
+            if !safe && !csrf_passed(req.request(), config) {
+                warn!("csrf check is failed: {} {}", req.method(), req.path());
+                let rsp = Response::<NoneBodyData>::forbidden("csrf check is failed")
+                    .with_error_code("csrf_failed")
+                    .finished_status_for(req.request());
+                return Ok(req.into_response(rsp).map_into_right_body());
+            }
+            let mut rsp = service.call(req).await?;
+            if let Some(cookie) = renewed_cookie(rsp.request(), config) {
+                if let Err(e) = rsp.response_mut().add_cookie(&cookie) {
+                    warn!("set renewed cookie with error: {:?}", e);
+                }
+            }
+            Ok(rsp.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn token(exp: u64, auth_time: Option<u64>) -> AccessToken {
+        let mut token = AccessToken::new(1, "account", "name", "app", Duration::from_secs(60));
+        token.iat = exp - 60;
+        token.exp = exp;
+        token.auth_time = auth_time;
+        token.family = Some("family".into());
+        token
+    }
+
+    fn config() -> TokenConfig {
+        TokenConfig {
+            access_lifetime: 600,
+            refresh_lifetime: 3600,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn renew_token_which_expires_soon() {
+        let renewed = renewed_token(&token(NOW + 30, Some(NOW)), 60, &config(), NOW).unwrap();
+        assert_eq!(renewed.exp, NOW + 600);
+        assert_eq!(renewed.iat, NOW);
+        assert_eq!(renewed.auth_time, Some(NOW));
+        assert!(renewed_token(&token(NOW + 120, Some(NOW)), 60, &config(), NOW).is_none());
+    }
+
+    #[test]
+    fn renewal_is_capped_by_refresh_lifetime_of_login() {
+        let auth_time = NOW - 3400;
+        let renewed = renewed_token(&token(NOW + 30, Some(auth_time)), 60, &config(), NOW);
+        assert_eq!(renewed.unwrap().exp, auth_time + 3600);
+        let auth_time = NOW - 3600;
+        assert!(renewed_token(&token(NOW + 30, Some(auth_time)), 60, &config(), NOW).is_none());
+    }
+
+    #[test]
+    fn origin_csrf_allows_only_same_origin_by_default() {
+        let mut config = CookieConfig {
+            csrf: CsrfMode::Origin,
+            ..Default::default()
+        };
+        let request = |origin: &str| {
+            actix_web::test::TestRequest::post()
+                .uri("http://api.example.com/orders")
+                .insert_header((header::HOST, "api.example.com"))
+                .insert_header((header::ORIGIN, origin))
+                .to_http_request()
+        };
+        assert!(csrf_passed(&request("http://api.example.com"), &config));
+        assert!(!csrf_passed(&request("http://evil.example.com"), &config));
+
+        config.trusted_origins = vec!["http://admin.example.com".into()];
+        assert!(csrf_passed(&request("http://admin.example.com"), &config));
+        assert!(!csrf_passed(&request("http://evil.example.com"), &config));
+    }
+
+    #[test]
+    fn token_without_login_is_not_renewed() {
+        assert!(renewed_token(&token(NOW + 30, None), 60, &config(), NOW).is_none());
+        let mut token = token(NOW + 30, Some(NOW));
+        token.family = None;
+        assert!(renewed_token(&token, 60, &config(), NOW).is_none());
+    }
+}
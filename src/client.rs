@@ -0,0 +1,157 @@
+use crate::response::{ErrData, Problem, PROBLEM_CONTENT_TYPE};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use thiserror::Error;
+
+/// Kind of error which is decoded from the `code` of Response
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApiErrorKind {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    NotAcceptable,
+    Conflict,
+    PayloadTooLarge,
+    TooManyRequests,
+    Internal,
+    BadGateway,
+    Unavailable,
+    Timeout,
+    Other,
+}
+
+impl ApiErrorKind {
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            400 => ApiErrorKind::BadRequest,
+            401 => ApiErrorKind::Unauthorized,
+            403 => ApiErrorKind::Forbidden,
+            404 => ApiErrorKind::NotFound,
+            406 => ApiErrorKind::NotAcceptable,
+            409 => ApiErrorKind::Conflict,
+            413 => ApiErrorKind::PayloadTooLarge,
+            429 => ApiErrorKind::TooManyRequests,
+            500 => ApiErrorKind::Internal,
+            502 => ApiErrorKind::BadGateway,
+            503 => ApiErrorKind::Unavailable,
+            504 => ApiErrorKind::Timeout,
+            _ => ApiErrorKind::Other,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    /// the request is not finished, such as connection refused or timeout
+    #[error("request with network error: {0}")]
+    Network(#[from] reqwest::Error),
+    /// the body is not a Response envelope or problem document
+    #[error("decode response of status {status} with error: {message}")]
+    Decode { status: u16, message: String },
+    /// the server returns an error envelope or problem document
+    #[error("api error {code}: {message}")]
+    Api {
+        kind: ApiErrorKind,
+        code: u16,
+        message: String,
+        error_code: Option<String>,
+        request_id: Option<String>,
+        details: Option<Value>,
+    },
+}
+
+impl ApiError {
+    /// None for network and decoding errors
+    pub fn kind(&self) -> Option<&ApiErrorKind> {
+        match self {
+            ApiError::Api { kind, .. } => Some(kind),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrData> for ApiError {
+    fn from(err: ErrData) -> Self {
+        ApiError::Api {
+            kind: ApiErrorKind::from_code(err.code),
+            code: err.code,
+            message: err.message,
+            error_code: err.error_code,
+            request_id: err.request_id,
+            details: err.details,
+        }
+    }
+}
+
+impl From<Problem> for ApiError {
+    fn from(problem: Problem) -> Self {
+        let extension = |name: &str| {
+            problem
+                .extensions
+                .get(name)
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string())
+        };
+        ApiError::Api {
+            kind: ApiErrorKind::from_code(problem.status),
+            code: problem.status,
+            message: problem.detail.clone().unwrap_or(problem.title.clone()),
+            error_code: extension("error_code"),
+            request_id: extension("request_id"),
+            details: problem.extensions.get("details").cloned(),
+        }
+    }
+}
+
+/// Decode the reply of webhttp server into `Result<T, ApiError>`
+#[async_trait::async_trait]
+pub trait ResponseExt {
+    async fn envelope<T: DeserializeOwned>(self) -> Result<T, ApiError>;
+}
+
+#[async_trait::async_trait]
+impl ResponseExt for reqwest::Response {
+    async fn envelope<T: DeserializeOwned>(self) -> Result<T, ApiError> {
+        let status = self.status().as_u16();
+        let is_problem = self
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |v| v.starts_with(PROBLEM_CONTENT_TYPE));
+        let body = self.bytes().await?;
+        decode_envelope(status, is_problem, &body)
+    }
+}
+
+/// Decode the body of envelope or problem document, `status` is the HTTP status
+pub fn decode_envelope<T: DeserializeOwned>(
+    status: u16,
+    is_problem: bool,
+    body: &[u8],
+) -> Result<T, ApiError> {
+    let decode_error = |message: String| ApiError::Decode { status, message };
+    if is_problem {
+        let problem = serde_json::from_slice::<Problem>(body)
+            .map_err(|e| decode_error(format!("invalid problem document: {}", e)))?;
+        return Err(problem.into());
+    }
+
+    let value = serde_json::from_slice::<Value>(body).map_err(|e| {
+        let text = String::from_utf8_lossy(&body[..std::cmp::min(body.len(), 256)]);
+        decode_error(format!("{}, body: {}", e, text))
+    })?;
+    match value.get("status").and_then(|v| v.as_bool()) {
+        Some(true) => {
+            let message = value.get("message").cloned().unwrap_or(Value::Null);
+            serde_json::from_value::<T>(message)
+                .map_err(|e| decode_error(format!("invalid message: {}", e)))
+        }
+        Some(false) => {
+            let err = serde_json::from_value::<ErrData>(value)
+                .map_err(|e| decode_error(format!("invalid error envelope: {}", e)))?;
+            Err(err.into())
+        }
+        None => Err(decode_error("status is not in the envelope".into())),
+    }
+}
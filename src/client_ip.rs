@@ -0,0 +1,166 @@
+use crate::error::{WebhttpError, WebhttpResult};
+use crate::options::ClientIpConfig;
+use crate::response::{NoneBodyData, Response};
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header::HeaderMap;
+use actix_web::{FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+
+static TRUSTED_PROXIES: OnceLock<Vec<IpNet>> = OnceLock::<Vec<IpNet>>::new();
+
+/// Parse the trusted proxies, both CIDR (10.0.0.0/8) and single ip (10.0.0.1) are allowed
+pub fn client_ip_init(config: &ClientIpConfig) -> WebhttpResult<&'static Vec<IpNet>> {
+    let mut trusted = Vec::<IpNet>::new();
+    for proxy in config.trusted_proxies.iter() {
+        let net = match proxy.parse::<IpNet>() {
+            Ok(net) => net,
+            Err(_) => match proxy.parse::<IpAddr>() {
+                Ok(ip) => IpNet::from(ip),
+                Err(_) => {
+                    return Err(WebhttpError::Config(format!(
+                        "trusted proxy is not a cidr or ip: {}",
+                        proxy
+                    )))
+                }
+            },
+        };
+        trusted.push(net);
+    }
+    Ok(TRUSTED_PROXIES.get_or_init(|| trusted))
+}
+
+fn trusted_proxies() -> &'static [IpNet] {
+    match TRUSTED_PROXIES.get() {
+        Some(trusted) => trusted.as_slice(),
+        None => &[],
+    }
+}
+
+/// Real ip of client, the forwarding headers are only honoured if the peer is trusted proxy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// check the client is in one of the networks, such as ip allow-list
+    pub fn is_in(&self, nets: &[IpNet]) -> bool {
+        nets.iter().any(|net| net.contains(&self.0))
+    }
+}
+
+impl std::fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    /// it fails if the peer is unknown, such as unix socket, use Option<ClientIp> for that case
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match client_ip(req) {
+            Some(ip) => ready(Ok(ClientIp(ip))),
+            None => {
+                let message = "client ip is unknown";
+                let rsp = Response::<NoneBodyData>::bad_request(message)
+                    .with_error_code("client_ip_unknown")
+                    .finished_for(req);
+                ready(Err(InternalError::from_response(message, rsp).into()))
+            }
+        }
+    }
+}
+
+/// Resolve real client ip of the request, None if the peer is unknown
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    resolve(req.peer_addr(), req.headers(), trusted_proxies())
+}
+
+/// The forwarding chain is walked from right to left, the first hop which is not trusted
+/// proxy is the client, so a client can't fake its ip by adding addresses on the left
+pub fn resolve(peer: Option<SocketAddr>, headers: &HeaderMap, trusted: &[IpNet]) -> Option<IpAddr> {
+    let peer = peer?.ip();
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return Some(peer);
+    }
+
+    let chain = forwarded_chain(headers)
+        .or_else(|| forwarded_for_chain(headers))
+        .or_else(|| real_ip_chain(headers))
+        .unwrap_or_default();
+    let mut client = peer;
+    for hop in chain.iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = *ip;
+                if !is_trusted(ip) {
+                    break;
+                }
+            }
+            // unknown or obfuscated node, the last trusted hop is used
+            None => break,
+        }
+    }
+    Some(client)
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    headers
+        .get_all(name)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// RFC 7239, such as: for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711"
+fn forwarded_chain(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let elements = header_values(headers, "forwarded");
+    if elements.is_empty() {
+        return None;
+    }
+    let chain = elements
+        .iter()
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node.trim().trim_matches('"')))
+        })
+        .collect();
+    Some(chain)
+}
+
+fn forwarded_for_chain(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let nodes = header_values(headers, "x-forwarded-for");
+    if nodes.is_empty() {
+        return None;
+    }
+    Some(nodes.iter().map(|node| parse_node(node)).collect())
+}
+
+fn real_ip_chain(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let node = headers.get("x-real-ip")?.to_str().ok()?.trim();
+    Some(vec![parse_node(node)])
+}
+
+/// node can be ip, ip:port, [ipv6] or [ipv6]:port
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .and_then(|v| v.parse::<IpAddr>().ok())
+}
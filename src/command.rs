@@ -1,41 +1,190 @@
-use super::websocket::{OutMessage, ROOM};
+use super::error::WebhttpResult;
+use super::websocket::{
+    resume_get, send_control, Control, OutMessage, StreamSignal, ACK_WAITERS, ROOM, STREAM_SIGNALS,
+};
 use crossbeam::queue::SegQueue;
 use dashmap::DashMap;
 use futures::future::Future;
+use futures::stream::{FuturesUnordered, StreamExt};
 use futures::task::Poll;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::task::Waker;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
 use webproto;
 
+/// Retry policy of acked indication
+#[derive(Clone, Debug)]
+pub struct AckOptions {
+    pub deadline: Duration,        // stop retrying after it
+    pub initial_backoff: Duration, // wait time before the first retry
+    pub max_backoff: Duration,     // backoff is doubled until this value
+}
+
+impl Default for AckOptions {
+    fn default() -> Self {
+        AckOptions {
+            deadline: Duration::from_secs(60),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(16),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Delivered {
+    pub id: String,
+    pub attempts: u32,
+}
+
+/// Resolved when the client acks the indication or the deadline is expired
+pub struct DeliveryHandle {
+    pub id: String,
+    receiver: oneshot::Receiver<WebhttpResult<Delivered>>,
+}
+
+impl Future for DeliveryHandle {
+    type Output = WebhttpResult<Delivered>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Self::Output> {
+        match std::pin::Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(CommandError::Cancelled {
+                event_id: self.id.clone(),
+            }
+            .into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ServerCommand<T: Send + Sync + serde::ser::Serialize> {
     pub queue: Arc<DashMap<String, Option<T>>>,
     pub wake_mgt: WakerManager,
+    /// key is event_id of streaming command, value receives the items, it's bounded by the
+    /// window of stream
+    pub streams: Arc<DashMap<String, mpsc::Sender<T>>>,
 }
 
 impl<T: Send + Sync + serde::ser::Serialize> ServerCommand<T> {
-    pub async fn send_indication(&self, client_id: String, in_data: T) -> anyhow::Result<()> {
+    pub async fn send_indication(&self, client_id: String, in_data: T) -> WebhttpResult<()> {
+        let data = webproto::Indication::<T>::encode(in_data).map_err(encode_failed)?;
+        // buffer it firstly, then it can be replayed when client is reconnected
+        let seq = match resume_get() {
+            Some(store) => store.push(&client_id, &data).await?,
+            None => None,
+        };
+
         let addr = match ROOM.sessions.entry(client_id.clone()) {
             dashmap::mapref::entry::Entry::Occupied(entry) => entry.get().1.clone(),
             dashmap::mapref::entry::Entry::Vacant(_) => {
-                return Err(anyhow::anyhow!(
-                    "socket mutex is not existed: {:?}",
-                    client_id
-                ));
+                if seq.is_some() {
+                    return Ok(());
+                }
+                return Err(CommandError::Disconnected { client_id }.into());
             }
         };
 
-        let data = webproto::Indication::<T>::encode(in_data)?;
-        let command = OutMessage { data: data };
+        let command = OutMessage { data, seq };
         let send_status = addr.send(command).await;
         if send_status.is_err() {
-            return Err(anyhow::anyhow!(
-                "send socket data to client with error: {:?}",
-                send_status.err()
-            ));
+            if seq.is_some() {
+                return Ok(());
+            }
+            return Err(CommandError::SendFailed(format!("{:?}", send_status.err())).into());
         }
-        anyhow::Ok(())
+        Ok(())
+    }
+
+    /// Send indication with at-least-once delivery, it's resent with backoff until the
+    /// client acks it by id or the deadline is expired, so the client should drop the
+    /// duplicated id. The retrying is running in background even if the handle is dropped.
+    pub fn send_indication_acked(
+        &self,
+        client_id: String,
+        in_data: T,
+        options: AckOptions,
+    ) -> WebhttpResult<DeliveryHandle> {
+        let data = webproto::Indication::<T>::encode(in_data).map_err(encode_failed)?;
+        let id = uuid::Uuid::new_v4().to_string();
+        let (ack_sender, mut ack_receiver) = oneshot::channel::<()>();
+        let waiter_key = (client_id.clone(), id.clone());
+        ACK_WAITERS.insert(waiter_key.clone(), ack_sender);
+
+        let (result_sender, result_receiver) = oneshot::channel();
+        let task_id = id.clone();
+        tokio::spawn(async move {
+            let id = task_id;
+            let deadline = tokio::time::Instant::now() + options.deadline;
+            let mut backoff = options.initial_backoff;
+            let mut attempt: u32 = 0;
+            let result = loop {
+                attempt += 1;
+                let frame = Control::Reliable {
+                    id: id.clone(),
+                    attempt,
+                    data: data.clone(),
+                };
+                // lookup the session in every attempt, the client may be reconnected
+                let addr = ROOM.sessions.get(&client_id).map(|e| e.1.clone());
+                match (addr, frame.encode()) {
+                    (Some(addr), Ok(frame)) => {
+                        if let Err(e) = addr
+                            .send(OutMessage {
+                                data: frame,
+                                seq: None,
+                            })
+                            .await
+                        {
+                            debug!("send acked indication {} with error: {:?}", id, e);
+                        }
+                    }
+                    (None, _) => {
+                        debug!("client {} is not connected, wait for retrying", client_id);
+                    }
+                    (_, Err(e)) => break Err(e),
+                }
+
+                let now = tokio::time::Instant::now();
+                if now >= deadline {
+                    break Err(CommandError::Timeout {
+                        event_id: id.clone(),
+                    }
+                    .into());
+                }
+                let wait = std::cmp::min(backoff, deadline - now);
+                match tokio::time::timeout(wait, &mut ack_receiver).await {
+                    Ok(_) => {
+                        break Ok(Delivered {
+                            id: id.clone(),
+                            attempts: attempt,
+                        })
+                    }
+                    Err(_) => {
+                        backoff = std::cmp::min(backoff * 2, options.max_backoff);
+                    }
+                }
+            };
+
+            if result.is_err() {
+                ACK_WAITERS.remove(&waiter_key);
+                warn!("acked indication is failed: {:?}", result);
+            }
+            let _ = result_sender.send(result);
+        });
+
+        Ok(DeliveryHandle {
+            id,
+            receiver: result_receiver,
+        })
     }
 
     pub async fn send_answer(
@@ -43,27 +192,22 @@ impl<T: Send + Sync + serde::ser::Serialize> ServerCommand<T> {
         client_id: String,
         event_id: String,
         in_data: impl Serialize,
-    ) -> anyhow::Result<()> {
+    ) -> WebhttpResult<()> {
         let addr = match ROOM.sessions.entry(client_id.clone()) {
             dashmap::mapref::entry::Entry::Occupied(entry) => entry.get().1.clone(),
             dashmap::mapref::entry::Entry::Vacant(_) => {
-                return Err(anyhow::anyhow!(
-                    "socket mutex is not existed: {:?}",
-                    client_id
-                ));
+                return Err(CommandError::Disconnected { client_id }.into());
             }
         };
 
-        let data = webproto::ClientCommand::<T>::encode(in_data, event_id.clone())?;
-        let command = OutMessage { data: data };
+        let data = webproto::ClientCommand::<T>::encode(in_data, event_id.clone())
+            .map_err(encode_failed)?;
+        let command = OutMessage { data, seq: None };
         let send_status = addr.send(command).await;
         if send_status.is_err() {
-            return Err(anyhow::anyhow!(
-                "send socket data to client with error: {:?}",
-                send_status.err()
-            ));
+            return Err(CommandError::SendFailed(format!("{:?}", send_status.err())).into());
         }
-        anyhow::Ok(())
+        Ok(())
     }
 
     pub fn new(queue: Arc<DashMap<String, Option<T>>>) -> Self {
@@ -72,52 +216,496 @@ impl<T: Send + Sync + serde::ser::Serialize> ServerCommand<T> {
         ServerCommand {
             queue: queue,
             wake_mgt: wake_mgt,
+            streams: Arc::new(DashMap::new()),
         }
     }
 
+    /// Route the reply of client to the waiting command or stream, it should be called by
+    /// ServiceCallback when ServerCommand message is received, return false if nobody waits
+    pub fn dispatch_reply(&self, event_id: String, data: T) -> bool {
+        if let Some(stream) = self.streams.get(&event_id).map(|v| v.clone()) {
+            return match stream.try_send(data) {
+                Ok(_) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    // client sends more items than its credits
+                    warn!("stream {} is overrun by client, it's failed", event_id);
+                    self.streams.remove(&event_id);
+                    if let Some(entry) = STREAM_SIGNALS.get(&event_id) {
+                        send_control(
+                            &entry.0,
+                            &Control::Cancel {
+                                event_id: event_id.clone(),
+                            },
+                        );
+                        let _ = entry.1.send(StreamSignal::Error {
+                            count: None,
+                            message: STREAM_OVERRUN.into(),
+                        });
+                    }
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            };
+        }
+        // not contained means timeout already
+        let mut waiting = false;
+        self.queue.entry(event_id).and_modify(|v| {
+            *v = Some(data);
+            waiting = true;
+        });
+        waiting
+    }
+
+    /// Send command whose reply is a stream of items with the same event_id, the client
+    /// can send `window` items before more credits are granted, and must finish it with
+    /// StreamEnd or StreamError control frame. Client is notified by Cancel control frame
+    /// if the stream is dropped before finished, or it sends more items than its credits.
+    pub async fn send_command_stream(
+        &self,
+        client_id: String,
+        in_data: impl Serialize,
+        options: StreamOptions,
+    ) -> WebhttpResult<CommandStream<T>> {
+        let event_id = uuid::Uuid::new_v4().to_string();
+        let addr = match ROOM.sessions.entry(client_id.clone()) {
+            dashmap::mapref::entry::Entry::Occupied(entry) => entry.get().1.clone(),
+            dashmap::mapref::entry::Entry::Vacant(_) => {
+                return Err(CommandError::Disconnected { client_id }.into());
+            }
+        };
+
+        let window = std::cmp::max(1, options.window);
+        let credit = Control::StreamCredit {
+            event_id: event_id.clone(),
+            credits: window,
+        }
+        .encode()?;
+        let data = webproto::ServerCommand::<T>::encode(in_data, event_id.clone())
+            .map_err(encode_failed)?;
+
+        // items in buffer are never more than the window if client follows its credits
+        let (item_sender, item_receiver) = mpsc::channel(window as usize);
+        let (signal_sender, signal_receiver) = mpsc::unbounded_channel();
+        self.streams.insert(event_id.clone(), item_sender);
+        STREAM_SIGNALS.insert(event_id.clone(), (client_id.clone(), signal_sender));
+        let stream = CommandStream {
+            event_id: event_id.clone(),
+            client_id,
+            items: item_receiver,
+            signals: signal_receiver,
+            streams: self.streams.clone(),
+            window,
+            granted: window as u64,
+            received: 0,
+            consumed: 0,
+            terminal: None,
+            outcome: None,
+        };
+
+        // credit is sent before command, then client knows it's a stream
+        for each in [credit, data] {
+            let send_status = addr
+                .send(OutMessage {
+                    data: each,
+                    seq: None,
+                })
+                .await;
+            if send_status.is_err() {
+                return Err(CommandError::SendFailed(format!("{:?}", send_status.err())).into());
+            }
+        }
+        Ok(stream)
+    }
+
     pub async fn send_command(
         &self,
         client_id: String,
         in_data: impl Serialize,
         timeout_seconds: u64,
-    ) -> anyhow::Result<T> {
+    ) -> WebhttpResult<T> {
+        let mut handle = self.send_command_handle(client_id, in_data).await?;
+        let resp = tokio::time::timeout(
+            tokio::time::Duration::from_secs(timeout_seconds),
+            &mut handle,
+        )
+        .await;
+        match resp {
+            Ok(resp) => resp,
+            Err(_) => {
+                // let client stop working on it
+                handle.cancel();
+                Err(CommandError::Timeout {
+                    event_id: handle.event_id.clone(),
+                }
+                .into())
+            }
+        }
+    }
+
+    /// Send command and return the handle of reply without timeout, the client is notified
+    /// by Cancel control frame if the handle is cancelled or dropped before the reply
+    pub async fn send_command_handle(
+        &self,
+        client_id: String,
+        in_data: impl Serialize,
+    ) -> WebhttpResult<CommandHandle<T>> {
         let event_id = uuid::Uuid::new_v4().to_string();
         let addr = match ROOM.sessions.entry(client_id.clone()) {
             dashmap::mapref::entry::Entry::Occupied(entry) => entry.get().1.clone(),
             dashmap::mapref::entry::Entry::Vacant(_) => {
-                return Err(anyhow::anyhow!(
-                    "socket mutex is not existed: {:?}",
-                    client_id
-                ));
+                return Err(CommandError::Disconnected { client_id }.into());
             }
         };
 
-        let data = webproto::ServerCommand::<T>::encode(in_data, event_id.clone())?;
-        let command = OutMessage { data: data };
+        let data = webproto::ServerCommand::<T>::encode(in_data, event_id.clone())
+            .map_err(encode_failed)?;
+        let command = OutMessage { data, seq: None };
         self.queue.insert(event_id.clone(), None);
         let send_status = addr.send(command).await;
         if send_status.is_err() {
             self.queue.remove(&event_id);
-            return Err(anyhow::anyhow!(
-                "send socket data to client with error: {:?}",
-                send_status.err()
-            ));
+            return Err(CommandError::SendFailed(format!("{:?}", send_status.err())).into());
         }
 
-        let client = ServerInternalCommand {
-            event_id: event_id.clone(),
+        Ok(CommandHandle {
+            event_id,
+            client_id,
             queue: self.queue.clone(),
             wake_mgt: self.wake_mgt.clone(),
-        };
+            finished: false,
+        })
+    }
 
-        let resp =
-            tokio::time::timeout(tokio::time::Duration::from_secs(timeout_seconds), client).await;
-        match resp {
-            Ok(resp) => return anyhow::Ok(resp),
-            Err(_) => {
-                return Err(anyhow::anyhow!("timeout for waiting for client response"));
+    /// Send the same command to many clients concurrently and gather the replies, the
+    /// clients which are not needed anymore by `mode` get Cancelled result and are notified
+    pub async fn send_command_many(
+        &self,
+        targets: Targets,
+        in_data: impl Serialize,
+        timeout_seconds: u64,
+        mode: GatherMode,
+    ) -> HashMap<String, WebhttpResult<T>> {
+        let client_ids = targets.client_ids();
+        let required = mode.required(client_ids.len());
+        let mut results = HashMap::<String, WebhttpResult<T>>::default();
+
+        let in_data = &in_data;
+        let sendings = client_ids.into_iter().map(|client_id| async move {
+            let handle = self.send_command_handle(client_id.clone(), in_data).await;
+            (client_id, handle)
+        });
+        let mut waiting = Vec::<(String, String)>::default();
+        let mut handles = Vec::<(String, CommandHandle<T>)>::default();
+        for (client_id, handle) in futures::future::join_all(sendings).await {
+            match handle {
+                Ok(handle) => {
+                    waiting.push((client_id.clone(), handle.event_id.clone()));
+                    handles.push((client_id, handle));
+                }
+                Err(e) => {
+                    results.insert(client_id, Err(e));
+                }
+            }
+        }
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(timeout_seconds);
+        let mut pending = handles
+            .into_iter()
+            .map(|(client_id, mut handle)| async move {
+                let resp = match tokio::time::timeout_at(deadline, &mut handle).await {
+                    Ok(resp) => resp,
+                    Err(_) => {
+                        handle.cancel();
+                        Err(CommandError::Timeout {
+                            event_id: handle.event_id.clone(),
+                        }
+                        .into())
+                    }
+                };
+                (client_id, resp)
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut replied = 0;
+        while let Some((client_id, resp)) = pending.next().await {
+            if resp.is_ok() {
+                replied += 1;
+            }
+            results.insert(client_id, resp);
+            if required.is_some_and(|required| replied >= required) {
+                break;
+            }
+        }
+        // the dropped handles notify their clients
+        drop(pending);
+        for (client_id, event_id) in waiting {
+            results
+                .entry(client_id)
+                .or_insert(Err(CommandError::Cancelled { event_id }.into()));
+        }
+        results
+    }
+}
+
+/// Clients of fan-out command
+#[derive(Clone, Debug)]
+pub enum Targets {
+    Sessions(Vec<String>), // session id list, it's actor_connid
+    Room(String),          // all sessions in the room
+}
+
+impl Targets {
+    pub fn client_ids(&self) -> Vec<String> {
+        match self {
+            Targets::Sessions(client_ids) => client_ids.clone(),
+            Targets::Room(room) => match ROOM.rooms.get(room) {
+                Some(sessions) => sessions.iter().map(|each| each.clone()).collect(),
+                None => Vec::new(),
+            },
+        }
+    }
+}
+
+/// When fan-out command stops waiting
+#[derive(Clone, Debug, PartialEq)]
+pub enum GatherMode {
+    All,           // all clients replied or timeout
+    Quorum,        // more than half of clients replied
+    FirstN(usize), // first N clients replied
+}
+
+impl GatherMode {
+    fn required(&self, total: usize) -> Option<usize> {
+        match self {
+            GatherMode::All => None,
+            GatherMode::Quorum => Some(total / 2 + 1),
+            GatherMode::FirstN(n) => Some(*n),
+        }
+    }
+}
+
+fn encode_failed(e: impl std::fmt::Debug) -> CommandError {
+    CommandError::SendFailed(format!("encode with error: {:?}", e))
+}
+
+#[derive(Error, Clone, Debug, PartialEq)]
+pub enum CommandError {
+    #[error("command is cancelled: {event_id}")]
+    Cancelled { event_id: String },
+    #[error("timeout for waiting for client response: {event_id}")]
+    Timeout { event_id: String },
+    #[error("client is disconnected: {client_id}")]
+    Disconnected { client_id: String },
+    #[error("send socket data to client with error: {0}")]
+    SendFailed(String),
+}
+
+/// Reply of the command which is sent to client
+pub struct CommandHandle<T: Send + Sync + serde::ser::Serialize> {
+    pub event_id: String,
+    client_id: String,
+    queue: Arc<DashMap<String, Option<T>>>,
+    wake_mgt: WakerManager,
+    finished: bool,
+}
+
+impl<T: Send + Sync + serde::ser::Serialize> CommandHandle<T> {
+    /// Stop waiting and notify client, awaiting the handle returns Cancelled after it
+    pub fn cancel(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.queue.remove(&self.event_id);
+        send_control(
+            &self.client_id,
+            &Control::Cancel {
+                event_id: self.event_id.clone(),
+            },
+        );
+    }
+}
+
+impl<T: Send + Sync + serde::ser::Serialize> Future for CommandHandle<T> {
+    type Output = WebhttpResult<T>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let value = this.queue.entry(this.event_id.clone());
+        match value {
+            dashmap::mapref::entry::Entry::Occupied(e) => {
+                if e.get().is_some() {
+                    let out_data = e.remove().unwrap();
+                    this.finished = true;
+                    return Poll::Ready(Ok(out_data));
+                }
+            }
+            dashmap::mapref::entry::Entry::Vacant(_) => {
+                this.finished = true;
+                return Poll::Ready(Err(CommandError::Cancelled {
+                    event_id: this.event_id.clone(),
+                }
+                .into()));
             }
         }
+
+        if !ROOM.sessions.contains_key(&this.client_id) {
+            this.finished = true;
+            this.queue.remove(&this.event_id);
+            return Poll::Ready(Err(CommandError::Disconnected {
+                client_id: this.client_id.clone(),
+            }
+            .into()));
+        }
+
+        this.wake_mgt.wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T: Send + Sync + serde::ser::Serialize> Drop for CommandHandle<T> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamOptions {
+    pub window: u32, // max items sent by client before more credits are granted
+}
+
+/// The stream is failed if client sends more items than its credits
+const STREAM_OVERRUN: &str = "stream credits are overrun by client";
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions { window: 32 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamOutcome {
+    Completed,
+    Failed(String),
+    Cancelled,
+}
+
+/// Items of streaming command, it's finished by the terminal frame from client
+pub struct CommandStream<T> {
+    pub event_id: String,
+    client_id: String,
+    items: mpsc::Receiver<T>,
+    signals: mpsc::UnboundedReceiver<StreamSignal>,
+    streams: Arc<DashMap<String, mpsc::Sender<T>>>,
+    window: u32,
+    granted: u64,
+    received: u64,
+    consumed: u32, // consumed items after last granting
+    terminal: Option<StreamSignal>,
+    outcome: Option<StreamOutcome>,
+}
+
+impl<T> CommandStream<T> {
+    /// None if the stream is still running
+    pub fn outcome(&self) -> Option<&StreamOutcome> {
+        self.outcome.as_ref()
+    }
+
+    /// Stop the stream and notify client, the received items are dropped
+    pub fn cancel(&mut self) {
+        if self.outcome.is_none() {
+            send_control(
+                &self.client_id,
+                &Control::Cancel {
+                    event_id: self.event_id.clone(),
+                },
+            );
+            self.finish(StreamOutcome::Cancelled);
+        }
+    }
+
+    fn finish(&mut self, outcome: StreamOutcome) {
+        self.streams.remove(&self.event_id);
+        STREAM_SIGNALS.remove(&self.event_id);
+        self.items.close();
+        self.outcome = Some(outcome);
+    }
+}
+
+impl<T> futures::Stream for CommandStream<T> {
+    type Item = T;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.outcome.is_some() {
+            return Poll::Ready(None);
+        }
+
+        if let Poll::Ready(Some(item)) = this.items.poll_recv(cx) {
+            this.received += 1;
+            this.consumed += 1;
+            if this.received > this.granted {
+                warn!("stream {} is overrun by client, it's failed", this.event_id);
+                send_control(
+                    &this.client_id,
+                    &Control::Cancel {
+                        event_id: this.event_id.clone(),
+                    },
+                );
+                this.finish(StreamOutcome::Failed(STREAM_OVERRUN.into()));
+                return Poll::Ready(None);
+            }
+            // grant more credits when half of window is consumed
+            if this.consumed >= std::cmp::max(1, this.window / 2) {
+                let credits = this.consumed;
+                this.consumed = 0;
+                this.granted += credits as u64;
+                send_control(
+                    &this.client_id,
+                    &Control::StreamCredit {
+                        event_id: this.event_id.clone(),
+                        credits,
+                    },
+                );
+            }
+            return Poll::Ready(Some(item));
+        }
+
+        while let Poll::Ready(Some(signal)) = this.signals.poll_recv(cx) {
+            if this.terminal.is_none() {
+                this.terminal = Some(signal);
+            }
+        }
+        // the terminal frame may arrive before the last items which pass by worker
+        let outcome = match &this.terminal {
+            Some(StreamSignal::End { count }) if this.received >= *count => {
+                Some(StreamOutcome::Completed)
+            }
+            Some(StreamSignal::Error { count, message })
+                if count.is_none_or(|count| this.received >= count) =>
+            {
+                Some(StreamOutcome::Failed(message.clone()))
+            }
+            _ => None,
+        };
+        if let Some(outcome) = outcome {
+            debug!("stream {} is finished: {:?}", this.event_id, outcome);
+            this.finish(outcome);
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for CommandStream<T> {
+    fn drop(&mut self) {
+        self.cancel();
     }
 }
 
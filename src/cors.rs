@@ -0,0 +1,382 @@
+use crate::error::{WebhttpError, WebhttpResult};
+use actix_cors::{Cors, CorsMiddleware};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{RequestHead, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use futures::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+
+/// `[cors]` of the config, see the example of WebOptions
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// policy of the paths which are not in any scope
+    #[serde(flatten)]
+    pub default: CorsPolicy,
+    /// the longest matched path_prefix is used
+    #[serde(default)]
+    pub scopes: Vec<ScopedCorsPolicy>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScopedCorsPolicy {
+    pub path_prefix: String,
+    #[serde(flatten)]
+    pub policy: CorsPolicy,
+}
+
+/// Empty methods or headers means any one is allowed. Default policy only allows the same
+/// origin, cross origin requests need `allowed_origins`, or "*" for any origin.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorsPolicy {
+    /// "*", exact origin such as "https://example.com", or wildcard subdomain such as
+    /// "https://*.example.com"
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: Option<usize>, // seconds of preflight cache
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            allowed_origins: Vec::new(),
+            allowed_methods: Vec::new(),
+            allowed_headers: Vec::new(),
+            expose_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: Some(3600),
+        }
+    }
+}
+
+impl CorsPolicy {
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| origin_matches(allowed, origin))
+    }
+
+    /// credentials can't be allowed for any origin
+    pub fn validate(&self) -> WebhttpResult<()> {
+        if self.allow_credentials && self.allowed_origins.iter().any(|v| v.eq("*")) {
+            return Err(WebhttpError::Config(
+                "cors allowed_origins can't be \"*\" when allow_credentials is true".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Cors middleware of this policy, it can also be used by `web::scope(..).wrap(..)`
+    /// if the scope is not covered by the app level policy
+    pub fn build(&self) -> Cors {
+        let mut cors = Cors::default();
+        if self.allowed_origins.iter().any(|v| v.eq("*")) {
+            cors = cors.allow_any_origin();
+        } else {
+            let policy = self.clone();
+            cors = cors.allowed_origin_fn(move |origin, head| {
+                origin.to_str().is_ok_and(|origin| {
+                    policy.is_origin_allowed(origin) || is_same_origin(head, origin)
+                })
+            });
+        }
+        cors = if self.allowed_methods.is_empty() {
+            cors.allow_any_method()
+        } else {
+            cors.allowed_methods(self.allowed_methods.iter().map(|v| v.as_str()))
+        };
+        cors = if self.allowed_headers.is_empty() {
+            cors.allow_any_header()
+        } else {
+            cors.allowed_headers(self.allowed_headers.iter().map(|v| v.as_str()))
+        };
+        cors = if self.expose_headers.is_empty() {
+            cors.expose_any_header()
+        } else {
+            cors.expose_headers(self.expose_headers.iter().map(|v| v.as_str()))
+        };
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+        cors.max_age(self.max_age)
+    }
+}
+
+/// Browsers also send Origin in same origin requests, such as POST and websocket upgrade,
+/// the host and port of Origin are compared with Host header
+pub fn is_same_origin(head: &RequestHead, origin: &str) -> bool {
+    let host = match head
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(host) => host,
+        None => return false,
+    };
+    match origin.split_once("://") {
+        Some((_, origin_host)) => origin_host.eq_ignore_ascii_case(host),
+        None => false,
+    }
+}
+
+/// The prefix matches whole path segments, so "/api" matches "/api/v1" but not "/apix"
+pub fn path_in_scope(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn origin_matches(allowed: &str, origin: &str) -> bool {
+    if allowed.eq("*") || allowed.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+    let (scheme, domain) = match allowed.split_once("://*.") {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let host = match origin.split_once("://") {
+        Some((origin_scheme, host)) if origin_scheme.eq_ignore_ascii_case(scheme) => host,
+        _ => return false,
+    };
+    // at least one label before the domain, and the host can't carry path or userinfo
+    match host.len().checked_sub(domain.len() + 1) {
+        Some(end) if end > 0 => {
+            host[end..].eq_ignore_ascii_case(&format!(".{}", domain))
+                && host[..end]
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+impl CorsConfig {
+    pub fn validate(&self) -> WebhttpResult<()> {
+        self.default.validate()?;
+        for scope in self.scopes.iter() {
+            scope.policy.validate().map_err(|e| {
+                WebhttpError::Config(format!("cors scope {}: {}", scope.path_prefix, e))
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn policy_of(&self, path: &str) -> &CorsPolicy {
+        self.scopes
+            .iter()
+            .filter(|v| path_in_scope(&v.path_prefix, path))
+            .max_by_key(|v| v.path_prefix.len())
+            .map_or(&self.default, |v| &v.policy)
+    }
+}
+
+static CORS_CONFIG: OnceLock<CorsConfig> = OnceLock::<CorsConfig>::new();
+
+pub fn cors_init(config: CorsConfig) -> WebhttpResult<&'static CorsConfig> {
+    config.validate()?;
+    Ok(CORS_CONFIG.get_or_init(|| config))
+}
+
+/// Used by websocket upgrade, which is not covered by cors of browser
+pub fn origin_allowed(path: &str, origin: &str) -> bool {
+    match CORS_CONFIG.get() {
+        Some(config) => config.policy_of(path).is_origin_allowed(origin),
+        None => true,
+    }
+}
+
+/// App level cors middleware, every request is handled by the policy of its scope
+pub struct ScopedCors {
+    config: CorsConfig,
+}
+
+impl ScopedCors {
+    pub fn new(config: CorsConfig) -> Self {
+        ScopedCors { config }
+    }
+}
+
+/// Inner service is shared by the cors middlewares of all policies
+pub struct SharedService<S>(Rc<S>);
+
+impl<S, Req> Service<Req> for SharedService<S>
+where
+    S: Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&self, req: Req) -> Self::Future {
+        self.0.call(req)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ScopedCors
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = ScopedCorsMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let inner = Rc::new(service);
+        let default = self
+            .config
+            .default
+            .build()
+            .new_transform(SharedService(inner.clone()));
+        let mut scopes = Vec::new();
+        for scope in self.config.scopes.iter() {
+            let middleware = scope
+                .policy
+                .build()
+                .new_transform(SharedService(inner.clone()));
+            scopes.push((scope.path_prefix.clone(), middleware));
+        }
+        Box::pin(async move {
+            let mut scoped = Vec::new();
+            for (prefix, middleware) in scopes {
+                scoped.push((prefix, middleware.await?));
+            }
+            // the longest prefix is matched firstly
+            scoped.sort_by_key(|v| std::cmp::Reverse(v.0.len()));
+            Ok(ScopedCorsMiddleware {
+                default: default.await?,
+                scoped,
+            })
+        })
+    }
+}
+
+pub struct ScopedCorsMiddleware<S> {
+    default: CorsMiddleware<SharedService<S>>,
+    scoped: Vec<(String, CorsMiddleware<SharedService<S>>)>,
+}
+
+impl<S, B> Service<ServiceRequest> for ScopedCorsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(default);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        for (prefix, middleware) in self.scoped.iter() {
+            if path_in_scope(prefix, req.path()) {
+                return middleware.call(req);
+            }
+        }
+        self.default.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn scoped(prefix: &str, origin: &str) -> ScopedCorsPolicy {
+        ScopedCorsPolicy {
+            path_prefix: prefix.into(),
+            policy: CorsPolicy {
+                allowed_origins: vec![origin.into()],
+                ..CorsPolicy::default()
+            },
+        }
+    }
+
+    #[test]
+    fn scope_matches_whole_segments() {
+        assert!(path_in_scope("/api", "/api"));
+        assert!(path_in_scope("/api", "/api/v1"));
+        assert!(path_in_scope("/api/", "/api/v1"));
+        assert!(!path_in_scope("/api", "/apix"));
+        assert!(!path_in_scope("/api", "/v1/api"));
+    }
+
+    #[test]
+    fn longest_scope_is_used() {
+        let config = CorsConfig {
+            default: CorsPolicy::default(),
+            scopes: vec![
+                scoped("/api", "https://a.com"),
+                scoped("/api/admin", "https://admin.a.com"),
+            ],
+        };
+        assert!(config
+            .policy_of("/api/admin/users")
+            .is_origin_allowed("https://admin.a.com"));
+        assert!(config
+            .policy_of("/api/users")
+            .is_origin_allowed("https://a.com"));
+        assert!(!config.policy_of("/apix").is_origin_allowed("https://a.com"));
+    }
+
+    #[test]
+    fn default_policy_allows_no_cross_origin() {
+        assert!(!CorsPolicy::default().is_origin_allowed("https://evil.com"));
+    }
+
+    #[test]
+    fn wildcard_subdomain() {
+        let allowed = "https://*.example.com";
+        assert!(origin_matches(allowed, "https://app.example.com"));
+        assert!(origin_matches(allowed, "https://a.b.example.com"));
+        assert!(!origin_matches(allowed, "https://example.com"));
+        assert!(!origin_matches(allowed, "http://app.example.com"));
+        assert!(!origin_matches(allowed, "https://evilexample.com"));
+        assert!(!origin_matches(allowed, "https://evil.com/.example.com"));
+    }
+
+    #[test]
+    fn credentials_with_any_origin_is_rejected() {
+        let policy = CorsPolicy {
+            allowed_origins: vec!["*".into()],
+            allow_credentials: true,
+            ..CorsPolicy::default()
+        };
+        assert!(policy.validate().is_err());
+        let config = CorsConfig {
+            default: CorsPolicy::default(),
+            scopes: vec![ScopedCorsPolicy {
+                path_prefix: "/api".into(),
+                policy,
+            }],
+        };
+        assert!(config.validate().is_err());
+        assert!(scoped("/api", "https://a.com").policy.validate().is_ok());
+    }
+
+    #[test]
+    fn same_origin_by_host() {
+        let req = TestRequest::default()
+            .insert_header((header::HOST, "a.com:8080"))
+            .to_http_request();
+        assert!(is_same_origin(req.head(), "https://a.com:8080"));
+        assert!(!is_same_origin(req.head(), "https://a.com"));
+        assert!(!is_same_origin(req.head(), "null"));
+    }
+}
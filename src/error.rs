@@ -0,0 +1,120 @@
+use crate::access_token::TokenError;
+use crate::command::CommandError;
+use crate::permission::PermissionError;
+use crate::response::{NoneBodyData, Response};
+use crate::websocket::ActorError;
+use actix_web::{http::StatusCode, HttpResponse};
+use serde::Serialize;
+use std::fmt::Debug;
+use thiserror::Error;
+
+pub type WebhttpResult<T> = std::result::Result<T, WebhttpError>;
+
+/// Error of all webhttp public APIs
+#[derive(Error, Debug)]
+pub enum WebhttpError {
+    #[error(transparent)]
+    Command(#[from] CommandError),
+    #[error(transparent)]
+    Token(#[from] TokenError),
+    #[error(transparent)]
+    Permission(#[from] PermissionError),
+    #[error(transparent)]
+    Actor(#[from] ActorError),
+    #[error("session is already existed: {session_id}")]
+    SessionExisted { session_id: String },
+    #[error("encode or decode json with error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("redis with error: {0}")]
+    Redis(#[from] fred::error::RedisError),
+    #[error("database with error: {0}")]
+    Database(#[from] sea_orm::DbErr),
+    #[error("http request with error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid config: {0}")]
+    Config(String),
+    #[error("password hash with error: {0}")]
+    PasswordHash(String),
+}
+
+impl WebhttpError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            WebhttpError::Command(e) => match e {
+                CommandError::Cancelled { .. } => StatusCode::SERVICE_UNAVAILABLE,
+                CommandError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+                CommandError::Disconnected { .. } => StatusCode::NOT_FOUND,
+                CommandError::SendFailed(_) => StatusCode::BAD_GATEWAY,
+            },
+            WebhttpError::Token(e) => match e {
+                TokenError::Encode { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNAUTHORIZED,
+            },
+            WebhttpError::Actor(e) => match e {
+                ActorError::RoomNotExisted(_) => StatusCode::NOT_FOUND,
+                ActorError::RoomAlreadyExisted(_) => StatusCode::CONFLICT,
+                ActorError::DataDecodeError => StatusCode::BAD_REQUEST,
+            },
+            WebhttpError::SessionExisted { .. } => StatusCode::CONFLICT,
+            WebhttpError::Json(_) => StatusCode::BAD_REQUEST,
+            WebhttpError::Http(_) => StatusCode::BAD_GATEWAY,
+            WebhttpError::Permission(_)
+            | WebhttpError::Config(_)
+            | WebhttpError::Redis(_)
+            | WebhttpError::Database(_)
+            | WebhttpError::PasswordHash(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable application error code which is used as `error_code` of Response
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            WebhttpError::Command(e) => match e {
+                CommandError::Cancelled { .. } => "command_cancelled",
+                CommandError::Timeout { .. } => "command_timeout",
+                CommandError::Disconnected { .. } => "client_disconnected",
+                CommandError::SendFailed(_) => "command_send_failed",
+            },
+            WebhttpError::Token(e) => match e {
+                TokenError::Missing => "token_missing",
+                TokenError::Expired => "token_expired",
+                TokenError::Invalid { .. } => "token_invalid",
+                TokenError::Encode { .. } => "token_encode_failed",
+                TokenError::WrongType { .. } => "token_wrong_type",
+                TokenError::Reused => "token_reused",
+                TokenError::Revoked => "token_revoked",
+            },
+            WebhttpError::Permission(_) => "permission_config_invalid",
+            WebhttpError::Actor(e) => match e {
+                ActorError::RoomNotExisted(_) => "room_not_existed",
+                ActorError::RoomAlreadyExisted(_) => "room_already_existed",
+                ActorError::DataDecodeError => "data_decode_failed",
+            },
+            WebhttpError::SessionExisted { .. } => "session_existed",
+            WebhttpError::Json(_) => "json_invalid",
+            WebhttpError::Redis(_) => "redis_error",
+            WebhttpError::Database(_) => "database_error",
+            WebhttpError::Http(_) => "upstream_error",
+            WebhttpError::Config(_) => "config_invalid",
+            WebhttpError::PasswordHash(_) => "password_hash_failed",
+        }
+    }
+}
+
+impl<T: Serialize + Debug> From<WebhttpError> for Response<T> {
+    fn from(err: WebhttpError) -> Self {
+        Response::new(err.status_code(), &err.to_string(), None).with_error_code(err.error_code())
+    }
+}
+
+impl actix_web::error::ResponseError for WebhttpError {
+    fn status_code(&self) -> StatusCode {
+        WebhttpError::status_code(self)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        Response::<NoneBodyData>::new(self.status_code(), &self.to_string(), None)
+            .with_error_code(self.error_code())
+            .finished()
+    }
+}
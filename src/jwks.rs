@@ -0,0 +1,148 @@
+use crate::access_token::{AccessToken, TokenError, TokenPermission};
+use crate::error::WebhttpResult;
+use crate::keyset::{keyset_get, KeySet};
+use crate::response::{NoneBodyData, Response};
+use actix_http::header::HeaderMap;
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::HttpResponse;
+use jsonwebtoken::decode_header;
+use jsonwebtoken::jwk::JwkSet;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+pub const JWKS_PATH: &str = "/.well-known/jwks.json";
+
+/// Public keys of the key set, HMAC keys are never published
+pub async fn jwks_handler() -> HttpResponse {
+    match keyset_get() {
+        Some(keys) => HttpResponse::Ok()
+            .insert_header((CACHE_CONTROL, "public, max-age=300"))
+            .json(keys.jwks()),
+        None => Response::<NoneBodyData>::nofound("jwt keys are not configured")
+            .with_error_code("jwks_not_configured")
+            .finished(),
+    }
+}
+
+/// TokenPermission which verifies tokens by the JWKS of a remote auth service
+///
+/// The keys are cached for `cache_ttl`, and refreshed when a token has unknown kid. The
+/// refreshing is at most once in `min_refresh_interval`, so tokens with random kid can't
+/// flood the auth service. Stale keys are still used if refreshing failed.
+pub struct RemoteJwks {
+    url: String,
+    client: reqwest::Client,
+    cache_ttl: Duration,
+    min_refresh_interval: Duration,
+    cache: RwLock<Option<(Arc<KeySet>, Instant)>>,
+    last_refresh: Mutex<Option<Instant>>,
+}
+
+impl RemoteJwks {
+    pub fn new(url: &str) -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        RemoteJwks {
+            url: url.to_string(),
+            client,
+            cache_ttl: Duration::from_secs(300),
+            min_refresh_interval: Duration::from_secs(10),
+            cache: RwLock::new(None),
+            last_refresh: Mutex::new(None),
+        }
+    }
+
+    pub fn with_cache_ttl(mut self, cache_ttl: Duration) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    pub fn with_min_refresh_interval(mut self, interval: Duration) -> Self {
+        self.min_refresh_interval = interval;
+        self
+    }
+
+    /// Fetch the JWKS, the cached keys are returned if it's just refreshed
+    pub async fn refresh(&self) -> WebhttpResult<Arc<KeySet>> {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if let Some(at) = *last_refresh {
+            if at.elapsed() < self.min_refresh_interval {
+                if let Some((keys, _)) = self.cache.read().await.clone() {
+                    return Ok(keys);
+                }
+            }
+        }
+        *last_refresh = Some(Instant::now());
+
+        let jwks = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<JwkSet>()
+            .await?;
+        let keys = Arc::new(KeySet::from_jwks(&jwks));
+        info!(
+            "jwks is refreshed from {}: {:?}",
+            self.url,
+            keys.keys().iter().map(|v| &v.kid).collect::<Vec<_>>()
+        );
+        *self.cache.write().await = Some((keys.clone(), Instant::now()));
+        Ok(keys)
+    }
+
+    async fn keys_for(&self, kid: Option<&str>) -> WebhttpResult<Arc<KeySet>> {
+        let cached = self.cache.read().await.clone();
+        let (keys, fetched_at) = match cached {
+            Some(cached) => cached,
+            None => return self.refresh().await,
+        };
+        let known = kid.is_none_or(|kid| keys.has_kid(kid));
+        if known && fetched_at.elapsed() < self.cache_ttl {
+            return Ok(keys);
+        }
+        if !known {
+            debug!("unknown kid {:?}, refresh jwks", kid);
+        }
+        match self.refresh().await {
+            Ok(keys) => Ok(keys),
+            Err(e) if known => {
+                warn!("refresh jwks with error, use the stale keys: {:?}", e);
+                Ok(keys)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn decode<T: DeserializeOwned>(&self, token: &str) -> WebhttpResult<T> {
+        let header = decode_header(token).map_err(TokenError::from)?;
+        let keys = self.keys_for(header.kid.as_deref()).await?;
+        keys.decode::<T>(token)
+    }
+
+    /// Same as decode, and the claims are verified by TokenConfig
+    pub async fn decode_access_token(&self, token: &str) -> WebhttpResult<AccessToken> {
+        let header = decode_header(token).map_err(TokenError::from)?;
+        let keys = self.keys_for(header.kid.as_deref()).await?;
+        AccessToken::decode_with_keys(token, &keys)
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenPermission for RemoteJwks {
+    async fn check_and_verify(&self, req: (HeaderMap, String)) -> WebhttpResult<AccessToken> {
+        let (headers, _path) = req;
+        let token = headers
+            .get("token")
+            .and_then(|v| v.to_str().ok())
+            .ok_or(TokenError::Missing)?;
+        self.decode_access_token(token).await
+    }
+}
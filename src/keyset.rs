@@ -0,0 +1,484 @@
+use crate::access_token::TokenError;
+use crate::error::{WebhttpError, WebhttpResult};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ed25519_dalek::pkcs8::DecodePublicKey;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+    PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header};
+use jsonwebtoken::{TokenData, Validation};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use tracing::{info, warn};
+
+/// `[[jwt_keys]]` of the config, see the example of WebOptions
+///
+/// The keys are PEM file path or PEM content, only the verifying services need public_key.
+/// After rotation, the old key is kept without `current` until its tokens are expired.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JwtKeyConfig {
+    pub kid: String,
+    /// HS512, RS256, ES256 or EdDSA
+    pub algorithm: Algorithm,
+    /// secret of HS512
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub private_key: Option<String>,
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// the key which signs new tokens, only one key can be current
+    #[serde(default)]
+    pub current: bool,
+    /// tokens of inactive key are rejected
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+pub struct JwtKey {
+    pub kid: Option<String>,
+    pub algorithm: Algorithm,
+    pub active: bool,
+    pub jwk: Option<Jwk>, // public key which can be published, None for HMAC key
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+}
+
+/// Signs by the current key, and verifies by any active key which has the kid of token
+pub struct KeySet {
+    keys: Vec<JwtKey>,
+    current: Option<usize>,
+}
+
+fn read_pem(value: &str) -> WebhttpResult<Vec<u8>> {
+    if value.trim_start().starts_with("-----BEGIN") {
+        return Ok(value.as_bytes().to_vec());
+    }
+    std::fs::read(value).map_err(|e| WebhttpError::Config(format!("read {}: {}", value, e)))
+}
+
+impl JwtKey {
+    pub fn from_config(config: &JwtKeyConfig) -> WebhttpResult<Self> {
+        let invalid = |reason: String| {
+            WebhttpError::Config(format!("jwt key {} is invalid: {}", config.kid, reason))
+        };
+        let mut jwk = None;
+        let (encoding, decoding) = match config.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = config
+                    .secret
+                    .as_ref()
+                    .ok_or_else(|| invalid("secret is missing".into()))?;
+                (
+                    Some(EncodingKey::from_secret(secret.as_bytes())),
+                    DecodingKey::from_secret(secret.as_bytes()),
+                )
+            }
+            algorithm => {
+                let public_key = config
+                    .public_key
+                    .as_ref()
+                    .ok_or_else(|| invalid("public_key is missing".into()))?;
+                let public_key = read_pem(public_key)?;
+                jwk = Some(public_jwk(&config.kid, algorithm, &public_key).map_err(invalid)?);
+                let private_key = match &config.private_key {
+                    Some(private_key) => Some(read_pem(private_key)?),
+                    None => None,
+                };
+                let (encoding, decoding) = match algorithm {
+                    Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => (
+                        private_key
+                            .map(|v| EncodingKey::from_rsa_pem(&v))
+                            .transpose(),
+                        DecodingKey::from_rsa_pem(&public_key),
+                    ),
+                    Algorithm::ES256 | Algorithm::ES384 => (
+                        private_key
+                            .map(|v| EncodingKey::from_ec_pem(&v))
+                            .transpose(),
+                        DecodingKey::from_ec_pem(&public_key),
+                    ),
+                    Algorithm::EdDSA => (
+                        private_key
+                            .map(|v| EncodingKey::from_ed_pem(&v))
+                            .transpose(),
+                        DecodingKey::from_ed_pem(&public_key),
+                    ),
+                    _ => return Err(invalid(format!("{:?} is not supported", algorithm))),
+                };
+                (
+                    encoding.map_err(|e| invalid(e.to_string()))?,
+                    decoding.map_err(|e| invalid(e.to_string()))?,
+                )
+            }
+        };
+        Ok(JwtKey {
+            kid: Some(config.kid.clone()),
+            algorithm: config.algorithm,
+            active: config.active,
+            jwk,
+            encoding,
+            decoding,
+        })
+    }
+}
+
+impl KeySet {
+    pub fn from_config(configs: &[JwtKeyConfig]) -> WebhttpResult<Self> {
+        let mut keys = Vec::new();
+        let mut current = None;
+        for config in configs.iter() {
+            let key = JwtKey::from_config(config)?;
+            if config.current {
+                if current.is_some() {
+                    return Err(WebhttpError::Config(format!(
+                        "jwt key {} is current, but there is another current key",
+                        config.kid
+                    )));
+                }
+                if key.encoding.is_none() || !key.active {
+                    return Err(WebhttpError::Config(format!(
+                        "jwt key {} is current, but it's inactive or has no private_key",
+                        config.kid
+                    )));
+                }
+                current = Some(keys.len());
+            }
+            keys.push(key);
+        }
+        Ok(KeySet { keys, current })
+    }
+
+    /// Legacy HS512 shared secret, tokens have no kid
+    pub fn from_secret(secret: &str) -> Self {
+        KeySet {
+            keys: vec![JwtKey {
+                kid: None,
+                algorithm: Algorithm::HS512,
+                active: true,
+                jwk: None,
+                encoding: Some(EncodingKey::from_secret(secret.as_bytes())),
+                decoding: DecodingKey::from_secret(secret.as_bytes()),
+            }],
+            current: Some(0),
+        }
+    }
+
+    /// Verify only keys of a remote JWKS, the keys without kid or supported algorithm are skipped
+    pub fn from_jwks(jwks: &JwkSet) -> Self {
+        let mut keys = Vec::new();
+        for jwk in jwks.keys.iter() {
+            let algorithm = match jwk_algorithm(jwk) {
+                Some(algorithm) => algorithm,
+                None => {
+                    warn!(
+                        "skip jwk without supported algorithm: {:?}",
+                        jwk.common.key_id
+                    );
+                    continue;
+                }
+            };
+            match (&jwk.common.key_id, DecodingKey::from_jwk(jwk)) {
+                (Some(kid), Ok(decoding)) => keys.push(JwtKey {
+                    kid: Some(kid.clone()),
+                    algorithm,
+                    active: true,
+                    jwk: Some(jwk.clone()),
+                    encoding: None,
+                    decoding,
+                }),
+                (kid, decoding) => {
+                    warn!("skip invalid jwk {:?}: {:?}", kid, decoding.err());
+                }
+            }
+        }
+        KeySet {
+            keys,
+            current: None,
+        }
+    }
+
+    /// Public keys of the active asymmetric keys, it's served as /.well-known/jwks.json
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .keys
+                .iter()
+                .filter(|key| key.active)
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+
+    pub fn has_kid(&self, kid: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|key| key.active && key.kid.as_deref() == Some(kid))
+    }
+
+    pub fn keys(&self) -> &[JwtKey] {
+        &self.keys
+    }
+
+    pub fn current(&self) -> Option<&JwtKey> {
+        self.current.map(|v| &self.keys[v])
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> WebhttpResult<String> {
+        let key = self.current().ok_or_else(|| TokenError::Encode {
+            reason: "no current key to sign token".into(),
+        })?;
+        let mut header = Header::new(key.algorithm);
+        header.kid = key.kid.clone();
+        let token = encode(&header, claims, key.encoding.as_ref().unwrap()).map_err(|e| {
+            TokenError::Encode {
+                reason: e.to_string(),
+            }
+        })?;
+        Ok(token)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> WebhttpResult<T> {
+        let validation = |key: &JwtKey| Validation::new(key.algorithm);
+        Ok(self.decode_with(token, validation)?.claims)
+    }
+
+    /// `validation` is created for each candidate key, so its algorithm can be set
+    pub fn decode_with<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: impl Fn(&JwtKey) -> Validation,
+    ) -> WebhttpResult<TokenData<T>> {
+        let header = decode_header(token).map_err(TokenError::from)?;
+        let mut candidates = self
+            .keys
+            .iter()
+            .filter(|key| key.active && key.algorithm == header.alg)
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .peekable();
+        if candidates.peek().is_none() {
+            return Err(TokenError::Invalid {
+                reason: format!("no active key for kid {:?} of {:?}", header.kid, header.alg),
+            }
+            .into());
+        }
+        // token without kid is tried with every key of its algorithm
+        let mut last_error = None;
+        for key in candidates {
+            match decode::<T>(token, &key.decoding, &validation(key)) {
+                Ok(data) => return Ok(data),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(TokenError::from(last_error.unwrap()).into())
+    }
+}
+
+fn jwk_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    if let Some(algorithm) = jwk.common.key_algorithm {
+        return algorithm.to_string().parse::<Algorithm>().ok();
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Some(Algorithm::RS256),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => Some(Algorithm::ES256),
+            EllipticCurve::P384 => Some(Algorithm::ES384),
+            _ => None,
+        },
+        AlgorithmParameters::OctetKeyPair(_) => Some(Algorithm::EdDSA),
+        AlgorithmParameters::OctetKey(_) => None,
+    }
+}
+
+fn ec_parameters(curve: EllipticCurve, point: &[u8], size: usize) -> AlgorithmParameters {
+    // uncompressed point: 0x04 + x + y
+    AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+        key_type: EllipticCurveKeyType::EC,
+        curve,
+        x: URL_SAFE_NO_PAD.encode(&point[1..1 + size]),
+        y: URL_SAFE_NO_PAD.encode(&point[1 + size..]),
+    })
+}
+
+/// Public JWK of PEM public key, the algorithm of SubjectPublicKeyInfo must be the one of
+/// `algorithm`
+fn public_jwk(kid: &str, algorithm: Algorithm, pem: &[u8]) -> Result<Jwk, String> {
+    let text = std::str::from_utf8(pem).map_err(|e| e.to_string())?;
+    let params = match algorithm {
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => {
+            let key = if text.contains("BEGIN RSA PUBLIC KEY") {
+                RsaPublicKey::from_pkcs1_pem(text).map_err(|e| e.to_string())?
+            } else {
+                RsaPublicKey::from_public_key_pem(text).map_err(|e| e.to_string())?
+            };
+            AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+            })
+        }
+        Algorithm::ES256 => {
+            let key = p256::PublicKey::from_public_key_pem(text).map_err(|e| e.to_string())?;
+            ec_parameters(
+                EllipticCurve::P256,
+                key.to_encoded_point(false).as_bytes(),
+                32,
+            )
+        }
+        Algorithm::ES384 => {
+            let key = p384::PublicKey::from_public_key_pem(text).map_err(|e| e.to_string())?;
+            ec_parameters(
+                EllipticCurve::P384,
+                key.to_encoded_point(false).as_bytes(),
+                48,
+            )
+        }
+        Algorithm::EdDSA => {
+            let key = ed25519_dalek::VerifyingKey::from_public_key_pem(text)
+                .map_err(|e| e.to_string())?;
+            AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(key.as_bytes()),
+            })
+        }
+        _ => return Err(format!("{:?} is not supported", algorithm)),
+    };
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: format!("{:?}", algorithm).parse::<KeyAlgorithm>().ok(),
+            key_id: Some(kid.to_string()),
+            ..Default::default()
+        },
+        algorithm: params,
+    })
+}
+
+static KEYSET: OnceLock<KeySet> = OnceLock::<KeySet>::new();
+
+/// Keys of jwt_keys option, or the shared jwt_secret if no key is configured
+pub fn keyset_init(configs: &[JwtKeyConfig], jwt_secret: Option<&str>) -> WebhttpResult<()> {
+    let keyset = if !configs.is_empty() {
+        KeySet::from_config(configs)?
+    } else if let Some(secret) = jwt_secret {
+        KeySet::from_secret(secret)
+    } else {
+        return Ok(());
+    };
+    info!(
+        "jwt keys are loaded: {:?}",
+        keyset.keys.iter().map(|v| &v.kid).collect::<Vec<_>>()
+    );
+    let _ = KEYSET.set(keyset);
+    Ok(())
+}
+
+/// return None when neither jwt_keys nor jwt_secret is configured
+pub fn keyset_get() -> Option<&'static KeySet> {
+    KEYSET.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::now_seconds;
+    use std::collections::HashMap;
+
+    // the fixture keys are generated by openssl and only used by tests
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/jwt/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn key_config(kid: &str, algorithm: Algorithm, name: &str) -> JwtKeyConfig {
+        JwtKeyConfig {
+            kid: kid.to_string(),
+            algorithm,
+            secret: None,
+            private_key: Some(fixture(&format!("{}.pem", name))),
+            public_key: Some(fixture(&format!("{}.pub.pem", name))),
+            current: true,
+            active: true,
+        }
+    }
+
+    fn claims() -> HashMap<String, serde_json::Value> {
+        let exp = now_seconds() + 60;
+        HashMap::from([
+            ("sub".to_string(), serde_json::json!("1")),
+            ("exp".to_string(), serde_json::json!(exp)),
+        ])
+    }
+
+    #[test]
+    fn sign_and_verify_by_jwks() {
+        for (algorithm, name) in [
+            (Algorithm::RS256, "rs256"),
+            (Algorithm::ES256, "es256"),
+            (Algorithm::ES384, "es384"),
+            (Algorithm::EdDSA, "eddsa"),
+        ] {
+            let keyset = KeySet::from_config(&[key_config(name, algorithm, name)]).unwrap();
+            let token = keyset.encode(&claims()).unwrap();
+            let decoded: HashMap<String, serde_json::Value> = keyset.decode(&token).unwrap();
+            assert_eq!(decoded["sub"], "1");
+
+            // the published jwk verifies tokens of the private key
+            let jwks = keyset.jwks();
+            assert_eq!(jwks.keys.len(), 1, "{:?}", algorithm);
+            let remote = KeySet::from_jwks(&jwks);
+            let decoded: HashMap<String, serde_json::Value> = remote.decode(&token).unwrap();
+            assert_eq!(decoded["sub"], "1");
+        }
+    }
+
+    #[test]
+    fn rsa_public_key_of_pkcs1_and_spki() {
+        let spki = std::fs::read(fixture("rs256.pub.pem")).unwrap();
+        let pkcs1 = std::fs::read(fixture("rs256.pkcs1.pub.pem")).unwrap();
+        let spki = public_jwk("rs", Algorithm::RS256, &spki).unwrap();
+        let pkcs1 = public_jwk("rs", Algorithm::RS256, &pkcs1).unwrap();
+        assert_eq!(spki, pkcs1);
+    }
+
+    #[test]
+    fn reject_key_of_other_algorithm() {
+        let es256 = std::fs::read(fixture("es256.pub.pem")).unwrap();
+        let es384 = std::fs::read(fixture("es384.pub.pem")).unwrap();
+        let eddsa = std::fs::read(fixture("eddsa.pub.pem")).unwrap();
+        let rsa = std::fs::read(fixture("rs256.pub.pem")).unwrap();
+        assert!(public_jwk("k", Algorithm::RS256, &es256).is_err());
+        assert!(public_jwk("k", Algorithm::ES256, &es384).is_err());
+        assert!(public_jwk("k", Algorithm::ES384, &es256).is_err());
+        assert!(public_jwk("k", Algorithm::EdDSA, &rsa).is_err());
+        assert!(public_jwk("k", Algorithm::ES256, &eddsa).is_err());
+        assert!(public_jwk("k", Algorithm::RS256, b"not a pem").is_err());
+
+        let config = key_config("k", Algorithm::RS256, "es256");
+        assert!(KeySet::from_config(&[config]).is_err());
+    }
+
+    #[test]
+    fn legacy_secret() {
+        let keyset = KeySet::from_secret("secret");
+        let token = keyset.encode(&claims()).unwrap();
+        let decoded: HashMap<String, serde_json::Value> = keyset.decode(&token).unwrap();
+        assert_eq!(decoded["sub"], "1");
+        assert!(keyset.jwks().keys.is_empty());
+        assert!(KeySet::from_secret("other")
+            .decode::<HashMap<String, serde_json::Value>>(&token)
+            .is_err());
+    }
+}
@@ -1,16 +1,53 @@
 pub mod command;
+pub mod error;
+pub use error::{WebhttpError, WebhttpResult};
+pub mod query;
 pub mod response;
 pub mod websocket;
-pub use response::{NoneBodyData, Response};
+pub use query::{PageQuery, SortOrder, SortQuery};
+pub use response::{NoneBodyData, Page, Response};
 pub mod access_token;
-pub use access_token::{get_token_and_path, AccessToken, TokenPermission};
+pub use access_token::{get_token_and_path, AccessToken, TokenConfig, TokenPermission, TokenType};
+pub mod refresh;
+pub use refresh::{issue_token_pair, TokenPair};
+pub mod revocation;
+pub use revocation::{revocation_get, RevocationCheck, RevocationStore};
+pub mod auth;
+pub use auth::{verify_request, verify_token};
+pub mod api_key;
+pub use api_key::{ApiKeyAuth, ApiKeyConfig, ApiKeyStore, ConfigApiKeyStore, DbApiKeyStore};
+#[cfg(feature = "login")]
+pub mod login;
+#[cfg(feature = "login")]
+pub use login::{hash_password, verify_password, DbUserStore, LoginConfig, LoginKit, UserStore};
+pub mod auth_cookie;
+pub use auth_cookie::{set_token_cookies, CookieConfig, CsrfMode, SameSitePolicy};
+pub mod keyset;
+pub use keyset::{keyset_get, JwtKeyConfig, KeySet};
+pub mod jwks;
+pub use jwks::RemoteJwks;
+pub mod client;
+pub use client::{ApiError, ApiErrorKind, ResponseExt};
 pub mod termenv;
 pub use termenv::{termenv_check, termenv_get, termenv_init};
 pub mod permission;
 pub use permission::*;
+pub mod options;
+pub use options::{
+    AccessLogConfig, ClientIpConfig, ErrorFormat, ResponseConfig, ResumeConfig, WebOptions,
+};
+pub mod access_log;
+pub use access_log::{request_id, RequestId, REQUEST_ID_HEADER};
+pub mod client_ip;
+pub use client_ip::{client_ip, ClientIp};
+pub mod cors;
+pub use cors::{CorsConfig, CorsPolicy, ScopedCorsPolicy};
+pub mod rate_limit;
+pub use rate_limit::{rate_limit_key_fn, Quota, RateLimitConfig, RateLimitKey, RateLimitRule};
 
 pub mod mysql;
 pub mod redis;
+mod util;
 pub mod webhttp;
 
 use actix::Actor;
@@ -20,7 +57,6 @@ use actix_web::web::PayloadConfig;
 use crossbeam::queue::SegQueue;
 use websocket::{ActorMsg, Connect, Disconnect, InMessage};
 
-use actix_cors::Cors;
 #[allow(unused_imports)]
 use actix_web::{
     dev::{Server, Service},
@@ -42,8 +78,10 @@ use tracing::*;
 
 #[allow(clippy::unused_async)]
 // #[cfg_attr(coverage, no_coverage)]
-pub async fn not_found() -> HttpResponse {
-    HttpResponse::NotFound().body("Not Found 404")
+pub async fn not_found(req: HttpRequest) -> HttpResponse {
+    Response::<NoneBodyData>::nofound("Not Found 404")
+        .with_error_code("not_found")
+        .finished_status_for(&req)
 }
 
 #[allow(clippy::unused_async)]
@@ -82,6 +120,7 @@ pub struct AppState {
     pub wsapi: Option<String>,
     pub token_check: Option<Arc<dyn crate::access_token::TokenPermission + Send + Sync>>,
     pub jwt_secret: Option<String>,
+    pub options: WebOptions,
 }
 
 pub async fn start(
@@ -99,8 +138,82 @@ pub async fn start(
     jwt_secret: Option<String>,                                         // jwt secret
     api_prefix: Option<String>, // api prefix url, such as /api/v1/test
 ) -> anyhow::Result<()> {
+    let options = StartOptions {
+        config,
+        ws_consumer,
+        ws_api,
+        worker_num,
+        thread_num,
+        database,
+        redis,
+        token_check,
+        jwt_secret,
+        api_prefix,
+        web: WebOptions::default(),
+    };
+    start_with_options(name, port, api_init, options).await
+}
+
+/// Arguments of start, with the optional server behaviours of WebOptions
+#[derive(Clone, Default)]
+pub struct StartOptions {
+    pub config: Option<serde_json::Value>, // configuration
+    pub ws_consumer: Option<Arc<dyn ServiceCallback>>, // Websocket consumer
+    pub ws_api: Option<String>,            // Websocket api register
+    pub worker_num: Option<usize>,         // actor number of websocket
+    pub thread_num: Option<usize>,         // web thread number
+    pub database: Option<DatabaseConnection>, // database connector
+    pub redis: Option<fred::prelude::RedisPool>, // redis connector
+    pub token_check: Option<Arc<dyn crate::access_token::TokenPermission + Send + Sync>>, // permissison
+    pub jwt_secret: Option<String>, // jwt secret
+    pub api_prefix: Option<String>, // api prefix url, such as /api/v1/test
+    pub web: WebOptions,            // optional server behaviours
+}
+
+/// Same as start, the arguments and the optional server behaviours are in StartOptions
+pub async fn start_with_options(
+    name: String,                                                       // server name
+    port: u16,                                                          // server port
+    api_init: impl Fn(&mut web::ServiceConfig) + Sync + Send + 'static, // outer api register
+    options: StartOptions,
+) -> anyhow::Result<()> {
+    let StartOptions {
+        config,
+        ws_consumer,
+        ws_api,
+        worker_num,
+        thread_num,
+        database,
+        redis,
+        token_check,
+        jwt_secret,
+        api_prefix,
+        web: options,
+    } = options;
     env_logger::init_from_env(Env::default().default_filter_or("info"));
 
+    response::response_init(options.response.clone());
+    client_ip::client_ip_init(&options.client_ip)?;
+    cors::cors_init(options.cors.clone())?;
+    access_token::token_init(options.token.clone());
+    keyset::keyset_init(&options.jwt_keys, jwt_secret.as_deref())?;
+    refresh::refresh_init(&options.token, redis.clone());
+    revocation::revocation_init(&options.token, redis.clone());
+    let refresh_endpoint = options.token.refresh_path.as_ref().map(|path| {
+        format!(
+            "{}/{}",
+            api_prefix.as_deref().unwrap_or("").trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    });
+    auth_cookie::cookie_init(options.token.cookie.clone(), refresh_endpoint);
+    if let Some(rate_limit) = options.rate_limit.as_ref() {
+        rate_limit::rate_limit_init(rate_limit.clone(), redis.clone());
+    }
+    if let Some(resume) = options.resume.as_ref() {
+        websocket::resume_init(resume.clone(), redis.clone());
+    }
+
     let new_addr_list = if ws_consumer.is_some() {
         let copied_consumer = ws_consumer.clone().unwrap();
         let worker_thread_number = if worker_num.is_none() {
@@ -148,8 +261,9 @@ pub async fn start(
         redis: redis,
         config: config,
         wsapi: ws_api,
-        token_check: token_check,
+        token_check: token_check.map(revocation::RevocationCheck::wrap),
         jwt_secret: jwt_secret,
+        options,
     };
     start_internal(state, name, port, api_init, thread_num, api_prefix).await?;
     anyhow::Ok(())
@@ -189,10 +303,33 @@ async fn start_internal(
     )?;
     metrics.registry.register(Box::new(found_errors.clone()))?;
 
+    let mut log_config = state.options.access_log.clone();
+    log_config
+        .quiet_paths
+        .push(format!("{}metrics", api_prefix));
+    let cors_config = state.options.cors.clone();
+    let jwks_endpoint = state.options.jwks_endpoint;
+    let refresh_path = state
+        .options
+        .token
+        .refresh_path
+        .as_ref()
+        .map(|path| format!("{}{}", api_prefix, path.trim_start_matches('/')));
+    let logout_path = state
+        .options
+        .token
+        .logout_path
+        .as_ref()
+        .map(|path| format!("{}{}", api_prefix, path.trim_start_matches('/')));
+
     let api_init = Arc::new(api_init);
     let payload_config = PayloadConfig::new(16 * 1024 * 1024);
     let json_payload_config = web::JsonConfig::default();
-    let json_payload_config = json_payload_config.limit(16 * 1024 * 1024);
+    let json_payload_config = json_payload_config
+        .limit(16 * 1024 * 1024)
+        .error_handler(response::json_error_handler);
+    let path_config = web::PathConfig::default().error_handler(response::path_error_handler);
+    let query_config = web::QueryConfig::default().error_handler(response::query_error_handler);
 
     let mut settings = actix_settings::Settings::from_default_template();
     actix_settings::Settings::override_field(&mut settings.actix.mode, "production")?;
@@ -208,52 +345,69 @@ async fn start_internal(
     }
 
     HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .expose_any_header()
-            .max_age(3600);
+        // FormConfig is not Send, so it's created in every worker
+        let form_config = web::FormConfig::default()
+            .limit(16 * 1024 * 1024)
+            .error_handler(response::form_error_handler);
+        let cors = cors::ScopedCors::new(cors_config.clone());
         let error_metrics = found_errors.clone();
+        let log_config = log_config.clone();
         App::new()
             .app_data(payload_config.clone())
             .app_data(json_payload_config.clone())
+            .app_data(path_config.clone())
+            .app_data(query_config.clone())
+            .app_data(form_config)
             .app_data(web::Data::new(state.clone()))
             .route(
                 format!("{}health", api_prefix).as_str(),
                 web::get().to(health_check),
             )
+            .configure(move |web_app| {
+                if jwks_endpoint {
+                    web_app.route(jwks::JWKS_PATH, web::get().to(jwks::jwks_handler));
+                }
+            })
+            .configure({
+                let refresh_path = refresh_path.clone();
+                let logout_path = logout_path.clone();
+                move |web_app| {
+                    if let Some(path) = refresh_path {
+                        web_app.route(path.as_str(), web::post().to(refresh::refresh_handler));
+                    }
+                    if let Some(path) = logout_path {
+                        web_app.route(path.as_str(), web::post().to(auth::logout_handler));
+                    }
+                }
+            })
             .configure(init_service(
                 state.clone(),
                 api_init.clone(),
                 api_prefix.clone(),
             ))
+            // render all failures with the same body as Response
+            .wrap(middleware::ErrorHandlers::new().default_handler(response::error_handler))
+            // csrf check of cookie authentication
+            .wrap(auth_cookie::CookieAuth)
+            // inside cors, so the browser can read 429 response
+            .wrap(rate_limit::RateLimit)
             .wrap(cors)
             // not use middlewar of logger, because the format is not same as tracing
             // .wrap(Logger::new("%a %r[%t]-%s %T %b"))
             .wrap(metrics.clone())
             .wrap_fn(move |req, srv| {
-                // let now = time::OffsetDateTime::now_utc().to_offset(offset!(+8));
-                let start_time = std::time::SystemTime::now()
-                    .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_micros();
-                // with port
-                let addr = req.request().peer_addr().unwrap().to_string();
-                // without port
-                // let addr = req
-                //     .request()
-                //     .connection_info()
-                //     .realip_remote_addr()
-                //     .unwrap()
-                //     .to_string();
-                let method = req.request().method().to_string();
-                let path = req.request().path().to_string();
-
-                let fut = srv.call(req);
+                let start_time = std::time::Instant::now();
+                let request_id = access_log::attach_request_id(&req);
+                // handlers and their logs are in this span
+                let span = info_span!("request", request_id = %request_id);
+                let fut = {
+                    let _enter = span.enter();
+                    srv.call(req)
+                };
                 let error_counter = error_metrics.clone();
+                let log_config = log_config.clone();
                 async move {
-                    let srv_response = fut.await?;
+                    let mut srv_response = fut.await?;
                     if let Some(err) = srv_response.response().error() {
                         let url = match srv_response.request().match_pattern() {
                             Some(pattern) => pattern,
@@ -265,38 +419,15 @@ async fn start_internal(
                             .with_label_values(&[url.as_str(), err_desc.as_str()])
                             .inc();
                     }
+                    access_log::echo_request_id(&mut srv_response, &request_id);
 
-                    let end_time = std::time::SystemTime::now()
-                        .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_micros();
-                    let micros_diff = (end_time - start_time) as f32 / 1000.0;
-
-                    // let log_info = format!(
-                    //     "[{:?}] {} {} {} {} {}ms",
-                    //     now,
-                    //     addr,
-                    //     method,
-                    //     path,
-                    //     srv_response.response().status().as_str(),
-                    //     micros_diff
-                    // );
-                    let log_info = format!(
-                        "{} {} {} {} {}ms",
-                        addr,
-                        method,
-                        path,
-                        srv_response.response().status().as_str(),
-                        micros_diff
-                    );
-                    if path.eq("/metrics") {
-                        debug!("{}", log_info)
-                    } else {
-                        info!("{}", log_info)
-                    }
+                    let latency_ms = start_time.elapsed().as_micros() as f32 / 1000.0;
+                    access_log::AccessLog::new(&srv_response, &request_id, latency_ms)
+                        .write(&log_config);
 
                     Ok(srv_response)
                 }
+                .instrument(span)
             })
             .default_service(web::route().to(not_found))
     })
@@ -0,0 +1,436 @@
+use crate::access_token::AccessToken;
+use crate::auth_cookie::{cookie_config, set_token_cookies, CookieSession};
+use crate::error::{WebhttpError, WebhttpResult};
+use crate::refresh::issue_token_pair;
+use crate::response::{NoneBodyData, Response};
+use crate::util::split_list;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use dashmap::DashMap;
+use fred::prelude::*;
+use sea_orm::{DatabaseConnection, DbBackend, FromQueryResult, Statement};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const REDIS_PREFIX: &str = "webhttp:login";
+
+/// Count one attempt, the ttl is set by the first attempt, or if the key has none
+const ATTEMPT_SCRIPT: &str = r#"
+local count = redis.call('INCR', KEYS[1])
+if count == 1 or redis.call('TTL', KEYS[1]) < 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+return count
+"#;
+
+/// argon2id hash in PHC string format, the salt is random
+pub fn hash_password(password: &str) -> WebhttpResult<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|v| v.to_string())
+        .map_err(|e| WebhttpError::PasswordHash(e.to_string()))
+}
+
+/// false if the password is wrong or the hash is not a PHC string
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            warn!("password hash is invalid: {}", e);
+            false
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LoginUser {
+    pub user_id: u64,
+    pub account: String,
+    pub name: String,
+    pub app_id: String,
+    pub password_hash: String,
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>,
+    pub disabled: bool,
+}
+
+#[async_trait::async_trait]
+pub trait UserStore {
+    async fn find_by_account(&self, account: &str) -> WebhttpResult<Option<LoginUser>>;
+}
+
+/// Users of mysql table, roles and scopes are separated by comma
+///
+/// ```sql
+/// CREATE TABLE users (
+///     id BIGINT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+///     account VARCHAR(128) NOT NULL UNIQUE,
+///     name VARCHAR(128) NOT NULL DEFAULT '',
+///     app_id VARCHAR(64) NOT NULL DEFAULT '',
+///     password_hash VARCHAR(255) NOT NULL,
+///     roles VARCHAR(1024) NOT NULL DEFAULT '',
+///     scopes VARCHAR(1024) NOT NULL DEFAULT '',
+///     disabled BOOLEAN NOT NULL DEFAULT FALSE
+/// );
+/// ```
+///
+pub struct DbUserStore {
+    db: DatabaseConnection,
+    table: String,
+}
+
+#[derive(Debug, FromQueryResult)]
+struct UserRow {
+    id: u64,
+    account: String,
+    name: String,
+    app_id: String,
+    password_hash: String,
+    roles: String,
+    scopes: String,
+    disabled: bool,
+}
+
+impl DbUserStore {
+    pub fn new(db: DatabaseConnection) -> Self {
+        DbUserStore {
+            db,
+            table: "users".into(),
+        }
+    }
+
+    pub fn with_table(mut self, table: &str) -> Self {
+        self.table = table.to_string();
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl UserStore for DbUserStore {
+    async fn find_by_account(&self, account: &str) -> WebhttpResult<Option<LoginUser>> {
+        let sql = format!(
+            "SELECT id, account, name, app_id, password_hash, roles, scopes, disabled \
+             FROM {} WHERE account = ?",
+            self.table
+        );
+        let row = UserRow::find_by_statement(Statement::from_sql_and_values(
+            DbBackend::MySql,
+            sql,
+            [account.into()],
+        ))
+        .one(&self.db)
+        .await?;
+        Ok(row.map(|row| LoginUser {
+            user_id: row.id,
+            account: row.account,
+            name: row.name,
+            app_id: row.app_id,
+            password_hash: row.password_hash,
+            roles: split_list(&row.roles),
+            scopes: split_list(&row.scopes),
+            disabled: row.disabled,
+        }))
+    }
+}
+
+/// Example
+///
+/// ```toml
+/// [login]
+/// max_failures = 5
+/// lockout_seconds = 900
+/// ```
+///
+/// The failures are counted by account. Guessing passwords of many accounts from one client
+/// should be limited by a rate limit rule of the login path with `key = "ip"`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoginConfig {
+    /// the account is locked after this number of continuous failures, the attempts being
+    /// verified are counted too
+    pub max_failures: u32,
+    /// seconds of lockout, failures are also forgotten after it
+    pub lockout_seconds: u64,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            max_failures: 5,
+            lockout_seconds: 900,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub account: String,
+    pub password: String,
+}
+
+/// Verify the password and issue the access and refresh tokens
+///
+/// ```ignore
+/// let kit = Arc::new(LoginKit::new(Arc::new(DbUserStore::new(db))).with_redis(redis));
+/// web_app.configure(kit.service("/api/v1/auth/login"));
+/// ```
+pub struct LoginKit {
+    store: Arc<dyn UserStore + Send + Sync>,
+    config: LoginConfig,
+    redis: Option<RedisPool>,
+    /// key is account, value is the failures and the time of first failure
+    failures: Arc<DashMap<String, (u32, Instant)>>,
+    /// the cleaner of failures is started by the first attempt
+    cleaner: Once,
+    /// verified for unknown accounts, so they take the same time as the known ones
+    dummy_hash: String,
+}
+
+impl LoginKit {
+    pub fn new(store: Arc<dyn UserStore + Send + Sync>) -> Self {
+        LoginKit {
+            store,
+            config: LoginConfig::default(),
+            redis: None,
+            failures: Arc::new(DashMap::new()),
+            cleaner: Once::new(),
+            dummy_hash: hash_password(&uuid::Uuid::new_v4().to_string()).unwrap_or_default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: LoginConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// count failures in redis, so all instances share the lockout
+    pub fn with_redis(mut self, redis: Option<RedisPool>) -> Self {
+        self.redis = redis;
+        self
+    }
+
+    /// Register the POST endpoint of login
+    pub fn service(self: &Arc<Self>, path: &str) -> impl Fn(&mut web::ServiceConfig) {
+        let kit = self.clone();
+        let path = path.to_string();
+        move |web_app| {
+            web_app.service(
+                web::resource(path.as_str())
+                    .app_data(web::Data::from(kit.clone()))
+                    .route(web::post().to(login_handler)),
+            );
+        }
+    }
+
+    fn failure_key(account: &str) -> String {
+        format!("{}:fail:{}", REDIS_PREFIX, account)
+    }
+
+    /// Count the attempt before the password is verified and return the count, so the
+    /// concurrent attempts can't pass the lockout. It's reset by the successful login.
+    async fn count_attempt(&self, account: &str) -> WebhttpResult<u32> {
+        if let Some(redis) = &self.redis {
+            let count: u32 = redis
+                .eval(
+                    ATTEMPT_SCRIPT,
+                    vec![Self::failure_key(account)],
+                    vec![self.config.lockout_seconds.to_string()],
+                )
+                .await?;
+            return Ok(count);
+        }
+        self.cleaner.call_once(|| self.start_cleaner());
+        let lockout = Duration::from_secs(self.config.lockout_seconds);
+        let mut entry = self
+            .failures
+            .entry(account.to_string())
+            .or_insert((0, Instant::now()));
+        if entry.1.elapsed() >= lockout {
+            *entry = (0, Instant::now());
+        }
+        entry.0 = entry.0.saturating_add(1);
+        Ok(entry.0)
+    }
+
+    /// failures are released after the lockout, the cleaner stops with the kit
+    fn start_cleaner(&self) {
+        let failures = Arc::downgrade(&self.failures);
+        let lockout = Duration::from_secs(self.config.lockout_seconds);
+        let interval = Duration::from_secs(std::cmp::max(1, self.config.lockout_seconds / 2));
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                match failures.upgrade() {
+                    Some(failures) => failures.retain(|_, entry| entry.1.elapsed() < lockout),
+                    None => break,
+                }
+            }
+        });
+    }
+
+    async fn reset_failures(&self, account: &str) -> WebhttpResult<()> {
+        if let Some(redis) = &self.redis {
+            let _: () = redis.del(Self::failure_key(account)).await?;
+            return Ok(());
+        }
+        self.failures.remove(account);
+        Ok(())
+    }
+
+    /// Some(user) if the password is right and the user is not disabled, the password is
+    /// verified in the blocking thread pool
+    pub async fn authenticate(
+        &self,
+        account: &str,
+        password: &str,
+    ) -> WebhttpResult<Option<LoginUser>> {
+        let user = self.store.find_by_account(account).await?;
+        let hash = user
+            .as_ref()
+            .map_or(self.dummy_hash.clone(), |v| v.password_hash.clone());
+        let password = password.to_string();
+        let verified = web::block(move || verify_password(&password, &hash))
+            .await
+            .map_err(|e| WebhttpError::PasswordHash(e.to_string()))?;
+        match user {
+            Some(user) if verified && !user.disabled => Ok(Some(user)),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn login_failed(
+    req: &HttpRequest,
+    code: StatusCode,
+    message: &str,
+    error_code: &str,
+) -> HttpResponse {
+    Response::<NoneBodyData>::new(code, message, None)
+        .with_error_code(error_code)
+        .finished_status_for(req)
+}
+
+pub async fn login_handler(
+    req: HttpRequest,
+    kit: web::Data<LoginKit>,
+    body: web::Json<LoginRequest>,
+) -> HttpResponse {
+    let LoginRequest { account, password } = body.into_inner();
+    let result: WebhttpResult<HttpResponse> = async {
+        if kit.count_attempt(&account).await? > kit.config.max_failures {
+            warn!("login of {} is locked", account);
+            let mut rsp = login_failed(
+                &req,
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many failures, try again later",
+                "login_locked",
+            );
+            rsp.headers_mut().insert(
+                actix_web::http::header::RETRY_AFTER,
+                kit.config.lockout_seconds.into(),
+            );
+            return Ok(rsp);
+        }
+        let user = match kit.authenticate(&account, &password).await? {
+            Some(user) => user,
+            None => {
+                return Ok(login_failed(
+                    &req,
+                    StatusCode::UNAUTHORIZED,
+                    "account or password is wrong",
+                    "login_failed",
+                ));
+            }
+        };
+        kit.reset_failures(&account).await?;
+
+        let claims = AccessToken::new(
+            user.user_id,
+            &user.account,
+            &user.name,
+            &user.app_id,
+            Duration::ZERO,
+        )
+        .with_roles(user.roles)
+        .with_scopes(user.scopes);
+        let pair = issue_token_pair(&claims).await?;
+        info!("user {} is logged in", user.user_id);
+        if cookie_config().is_some() {
+            let mut rsp = Response::success(CookieSession::from(&pair)).finished_for(&req);
+            set_token_cookies(&mut rsp, &pair);
+            return Ok(rsp);
+        }
+        Ok(Response::success(pair).finished_for(&req))
+    }
+    .await;
+    match result {
+        Ok(rsp) => rsp,
+        Err(e) => Response::<NoneBodyData>::from(e).finished_for(&req),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MemoryUserStore(LoginUser);
+
+    #[async_trait::async_trait]
+    impl UserStore for MemoryUserStore {
+        async fn find_by_account(&self, account: &str) -> WebhttpResult<Option<LoginUser>> {
+            Ok(Some(self.0.clone()).filter(|v| v.account.eq(account)))
+        }
+    }
+
+    fn login_kit(disabled: bool) -> LoginKit {
+        let user = LoginUser {
+            user_id: 1,
+            account: "alice".into(),
+            password_hash: hash_password("secret").unwrap(),
+            disabled,
+            ..Default::default()
+        };
+        LoginKit::new(Arc::new(MemoryUserStore(user))).with_config(LoginConfig {
+            max_failures: 2,
+            lockout_seconds: 60,
+        })
+    }
+
+    #[test]
+    fn verify_hashed_password() {
+        let hash = hash_password("secret").unwrap();
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("wrong", &hash));
+        assert!(!verify_password("secret", "not a phc string"));
+    }
+
+    #[actix_web::test]
+    async fn authenticate_user() {
+        let kit = login_kit(false);
+        assert!(kit.authenticate("alice", "secret").await.unwrap().is_some());
+        assert!(kit.authenticate("alice", "wrong").await.unwrap().is_none());
+        assert!(kit.authenticate("bob", "secret").await.unwrap().is_none());
+        let kit = login_kit(true);
+        assert!(kit.authenticate("alice", "secret").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn attempts_are_counted_before_verifying() {
+        let kit = login_kit(false);
+        assert_eq!(kit.count_attempt("alice").await.unwrap(), 1);
+        assert_eq!(kit.count_attempt("alice").await.unwrap(), 2);
+        // the third attempt is locked, even if the former ones are still being verified
+        assert!(kit.count_attempt("alice").await.unwrap() > kit.config.max_failures);
+        assert_eq!(kit.count_attempt("bob").await.unwrap(), 1);
+        kit.reset_failures("alice").await.unwrap();
+        assert_eq!(kit.count_attempt("alice").await.unwrap(), 1);
+    }
+}
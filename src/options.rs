@@ -0,0 +1,172 @@
+use crate::access_token::TokenConfig;
+use crate::cors::CorsConfig;
+use crate::keyset::JwtKeyConfig;
+use crate::rate_limit::RateLimitConfig;
+use serde::{Deserialize, Serialize};
+
+/// Optional server behaviours, all of them are disabled by default
+///
+/// Example of the config sections, every section is optional
+///
+/// ```toml
+/// [resume]
+/// grace_seconds = 60
+/// max_buffered = 256
+/// use_redis = false
+///
+/// [response]
+/// http_status = true
+/// error_format = "negotiate"
+/// problem_type_base = "https://example.com/problems/"
+///
+/// [access_log]
+/// json = true
+/// quiet_paths = ["/health"]
+///
+/// [client_ip]
+/// trusted_proxies = ["127.0.0.1", "10.0.0.0/8"]
+///
+/// [cors]
+/// allowed_origins = ["https://example.com", "https://*.example.com"]
+/// allowed_methods = ["GET", "POST"]
+/// allow_credentials = true
+/// max_age = 3600
+///
+/// [[cors.scopes]]
+/// path_prefix = "/api/v1/admin"
+/// allowed_origins = ["https://admin.example.com"]
+///
+/// [rate_limit]
+/// use_redis = true
+///
+/// [[rate_limit.rules]]
+/// path = "/api/v1/login"
+/// methods = ["POST"]
+/// key = "ip"
+/// capacity = 5
+/// per_seconds = 60
+///
+/// [[rate_limit.rules]]
+/// path = "/api/v1/"
+/// key = "user"
+/// capacity = 100
+/// per_seconds = 1
+///
+/// [rate_limit.websocket]
+/// capacity = 50
+/// per_seconds = 1
+/// close_on_exceed = true
+///
+/// [token]
+/// issuer = "https://auth.example.com"
+/// audience = ["order-service"]
+/// leeway = 30
+/// legacy_until = 1735689600
+/// access_lifetime = 7200
+/// refresh_lifetime = 604800
+/// refresh_path = "auth/refresh"
+/// logout_path = "auth/logout"
+/// revocation_use_redis = true
+///
+/// [token.cookie]
+/// domain = "admin.example.com"
+/// same_site = "strict"
+/// csrf = "double_submit"
+/// renew_before = 600
+///
+/// [[jwt_keys]]
+/// kid = "2024-06"
+/// algorithm = "RS256"
+/// private_key = "/etc/webhttp/jwt-2024-06.pem"
+/// public_key = "/etc/webhttp/jwt-2024-06.pub.pem"
+/// current = true
+///
+/// [[jwt_keys]]
+/// kid = "2024-01"
+/// algorithm = "RS256"
+/// public_key = "/etc/webhttp/jwt-2024-01.pub.pem"
+/// ```
+///
+/// The stores with a `use_redis` option keep their state in AppState.redis if it's set,
+/// or in process memory.
+#[derive(Clone, Debug, Default)]
+pub struct WebOptions {
+    pub resume: Option<ResumeConfig>, // resumable websocket session
+    pub response: ResponseConfig,     // how Response is rendered
+    pub access_log: AccessLogConfig,  // format of access log
+    pub client_ip: ClientIpConfig,    // real client ip behind proxies
+    pub cors: CorsConfig,             // cors of http api and origin check of websocket
+    pub rate_limit: Option<RateLimitConfig>, // http and websocket rate limit
+    pub jwks_endpoint: bool,          // serve public keys of jwt_keys at /.well-known/jwks.json
+    pub token: TokenConfig,           // standard claims of AccessToken
+    pub jwt_keys: Vec<JwtKeyConfig>,  // asymmetric or rotated jwt keys, jwt_secret is used if empty
+}
+
+/// `[resume]` of the config, see the example of WebOptions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResumeConfig {
+    /// how long the buffered messages are kept after the socket is dropped
+    pub grace_seconds: u64,
+    /// max buffered indication number for each session
+    pub max_buffered: usize,
+    /// keep the buffer in AppState.redis instead of process memory
+    #[serde(default)]
+    pub use_redis: bool,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        ResumeConfig {
+            grace_seconds: 60,
+            max_buffered: 256,
+            use_redis: false,
+        }
+    }
+}
+
+/// `[response]` of the config, see the example of WebOptions
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ResponseConfig {
+    /// use the real status as HTTP status, otherwise HTTP status is always 200 and the
+    /// real one is only in the `code` field of json body
+    #[serde(default)]
+    pub http_status: bool,
+    #[serde(default)]
+    pub error_format: ErrorFormat,
+    /// `type` of problem document is this base + error_code, otherwise it's about:blank
+    #[serde(default)]
+    pub problem_type_base: Option<String>,
+}
+
+/// Body format of error response
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorFormat {
+    /// {status, code, message} envelope
+    #[default]
+    Envelope,
+    /// RFC 7807 application/problem+json, it always uses the real HTTP status
+    Problem,
+    /// Problem if Accept header of request has application/problem+json, otherwise Envelope
+    Negotiate,
+}
+
+/// `[access_log]` of the config, see the example of WebOptions
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    /// write each access log as one json line, otherwise as tracing fields
+    #[serde(default)]
+    pub json: bool,
+    /// access logs of these paths are in debug level, metrics endpoint is always included
+    #[serde(default)]
+    pub quiet_paths: Vec<String>,
+}
+
+/// `[client_ip]` of the config, see the example of WebOptions
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClientIpConfig {
+    /// Forwarded, X-Forwarded-For and X-Real-IP are only honoured if the peer is in these
+    /// networks, so peer address is always used by default
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
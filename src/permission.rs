@@ -1,11 +1,62 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use crate::error::WebhttpResult;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{self, MapAccess, SeqAccess, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Error, Clone, Debug, PartialEq)]
+pub enum PermissionError {
+    #[error("malformed action key {key} of page {page}: {reason}")]
+    MalformedKey {
+        page: String,
+        key: String,
+        reason: String,
+    },
+    #[error("malformed role value {value} of action {page}.{key}: {reason}")]
+    MalformedValue {
+        page: String,
+        key: String,
+        value: String,
+        reason: String,
+    },
+    #[error("malformed grant {grant} of role {role}: {reason}")]
+    MalformedGrant {
+        role: String,
+        grant: String,
+        reason: String,
+    },
+    #[error("role {role} inherits unknown role {parent}")]
+    UnknownParent { role: String, parent: String },
+    #[error("role inheritance has a cycle: {cycle}")]
+    InheritanceCycle { cycle: String },
+    /// location is the path in config, such as permission.user.usermgt.roles.admin
+    #[error("invalid permission config at {location}: {reason}")]
+    Invalid { location: String, reason: String },
+}
+
+fn invalid(location: String, reason: String) -> PermissionError {
+    PermissionError::Invalid { location, reason }
+}
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
 pub struct RpItem {
     pub eng: String,
     pub chn: String,
     pub enabled: bool, // Action is activated or not
+    /// key is locale, such as en or zh
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl RpItem {
+    /// label of the locale, the name is used if it's not given
+    pub fn label(&self, locale: &str) -> &str {
+        self.labels
+            .get(locale)
+            .map_or(self.eng.as_str(), |v| v.as_str())
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
@@ -15,36 +66,529 @@ pub struct RpAction {
 
 /// Example
 ///
-/// '''toml
+/// ```toml
+/// name = "testapp"
+///
+/// [role]
+/// default = []
+/// operator = ["alice"]
+/// admin = ["admin"]
+/// auditor = ["bob"]
 ///
-///     name = "testapp"
+/// [inherit]
+/// admin = ["operator"]
 ///
-///     [role]
-///     default = []
-///     admin = ["admin"]
+/// [grant]
+/// operator = ["user:*"]
+/// admin = ["*:*", "!user:delete"]
 ///
-///     [permission."user"]
-///     "usermgt-xxx" = "default-false:admin-true"
-///     "userread-xxx" = "default-true:admin-true"
-/// '''
+/// [permission.user.usermgt]
+/// label = { en = "User management", zh = "用户管理" }
+/// roles = { default = false, admin = true }
+///
+/// [permission.user.user-read]
+/// label = { en = "Read users", zh = "查看用户" }
+/// roles = { default = true, admin = true, auditor = "deny" }
+///
+/// [[permission.order]]
+/// name = "order-export"
+/// label = { en = "Export orders" }
+/// roles = ["admin", "operator"]
+/// deny = ["auditor"]
+/// ```
+///
+/// A role has the permissions of the roles it inherits. Grants are `page:action`, where
+/// `*` matches any page or action, and a trailing `*` matches the prefix. A grant with `!`
+/// and the `deny` role value are explicit denies, which override every allow of the user.
+///
+/// The old format `"usermgt-xxx" = "default-false:admin-true"` is still accepted, and
+/// RpConfig::to_structured converts it. Its roles which are not in [role] are ignored with a
+/// warning as before, but they are errors in the structured format.
 ///
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
 pub struct RpConfig {
     pub name: String,
     pub role: RpInputRole,
     pub permission: RpInputPermission,
+    #[serde(default)]
+    pub inherit: RpInputInherit,
+    #[serde(default)]
+    pub grant: RpInputGrant,
 }
 
 // const ROLE_INTERNAL: &str = "__role_internal__";
 pub type RpInputRole = HashMap<String, Vec<String>>;
-pub type RpInputPermission = HashMap<String, HashMap<String, String>>;
+/// key is page name
+pub type RpInputPermission = HashMap<String, RpInputPage>;
+/// key is role name, value is its parent roles
+pub type RpInputInherit = HashMap<String, Vec<String>>;
+/// key is role name, value is `page:action` grants
+pub type RpInputGrant = HashMap<String, Vec<String>>;
+
+/// Value of a role in the roles table of action
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RpRoleGrant {
+    Allow, // true
+    Unset, // false
+    Deny,  // "deny"
+}
+
+/// role name and its grant of an action
+type RpRoleGrants = Vec<(String, RpRoleGrant)>;
+
+impl RpRoleGrant {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "true" | "allow" => Some(RpRoleGrant::Allow),
+            "false" => Some(RpRoleGrant::Unset),
+            "deny" => Some(RpRoleGrant::Deny),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for RpRoleGrant {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            RpRoleGrant::Allow => serializer.serialize_bool(true),
+            RpRoleGrant::Unset => serializer.serialize_bool(false),
+            RpRoleGrant::Deny => serializer.serialize_str("deny"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RpRoleGrant {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct GrantVisitor;
+
+        impl<'de> Visitor<'de> for GrantVisitor {
+            type Value = RpRoleGrant;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("true, false or \"deny\"")
+            }
+
+            fn visit_bool<E: de::Error>(self, value: bool) -> Result<Self::Value, E> {
+                Ok(if value {
+                    RpRoleGrant::Allow
+                } else {
+                    RpRoleGrant::Unset
+                })
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                RpRoleGrant::parse(value)
+                    .ok_or_else(|| E::invalid_value(Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(GrantVisitor)
+    }
+}
+
+/// `{ admin = true, auditor = "deny" }`, or the list of allowed roles `["admin"]`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum RpInputRoles {
+    Map(BTreeMap<String, RpRoleGrant>),
+    List(Vec<String>),
+}
+
+impl Default for RpInputRoles {
+    fn default() -> Self {
+        RpInputRoles::Map(BTreeMap::new())
+    }
+}
+
+impl<'de> Deserialize<'de> for RpInputRoles {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RolesVisitor;
+
+        impl<'de> Visitor<'de> for RolesVisitor {
+            type Value = RpInputRoles;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a table of role grants or a list of roles")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                BTreeMap::deserialize(MapAccessDeserializer::new(map)).map(RpInputRoles::Map)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(RpInputRoles::List)
+            }
+        }
+
+        deserializer.deserialize_any(RolesVisitor)
+    }
+}
+
+/// Action of the structured format, name is the key of table form
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RpInputAction {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// key is locale, such as en or zh
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub label: BTreeMap<String, String>,
+    #[serde(default)]
+    pub roles: RpInputRoles,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+}
+
+impl RpInputAction {
+    fn grants(&self) -> RpRoleGrants {
+        let mut grants = match &self.roles {
+            RpInputRoles::Map(roles) => roles.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            RpInputRoles::List(roles) => roles
+                .iter()
+                .map(|v| (v.clone(), RpRoleGrant::Allow))
+                .collect::<Vec<_>>(),
+        };
+        grants.extend(self.deny.iter().map(|v| (v.clone(), RpRoleGrant::Deny)));
+        grants
+    }
+}
+
+/// `"eng-chn" = "role-bool:role-bool"` of the old format, or the structured table
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum RpInputActionValue {
+    Legacy(String),
+    Action(RpInputAction),
+}
+
+impl<'de> Deserialize<'de> for RpInputActionValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ActionVisitor;
+
+        impl<'de> Visitor<'de> for ActionVisitor {
+            type Value = RpInputActionValue;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a table of action or a string of the old format")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(RpInputActionValue::Legacy(value.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                RpInputAction::deserialize(MapAccessDeserializer::new(map))
+                    .map(RpInputActionValue::Action)
+            }
+        }
+
+        deserializer.deserialize_any(ActionVisitor)
+    }
+}
+
+/// Actions of a page, keyed by name or a list with names
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum RpInputPage {
+    Map(BTreeMap<String, RpInputActionValue>),
+    List(Vec<RpInputAction>),
+}
+
+impl<'de> Deserialize<'de> for RpInputPage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PageVisitor;
+
+        impl<'de> Visitor<'de> for PageVisitor {
+            type Value = RpInputPage;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a table or a list of actions")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                BTreeMap::deserialize(MapAccessDeserializer::new(map)).map(RpInputPage::Map)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(RpInputPage::List)
+            }
+        }
+
+        deserializer.deserialize_any(PageVisitor)
+    }
+}
+
+/// Action of any format, with the location in config
+struct RpParsedAction {
+    page: String,
+    name: String,
+    labels: BTreeMap<String, String>,
+    grants: RpRoleGrants,
+    location: String,
+    /// action of the old format, its unknown roles are ignored with a warning
+    legacy: bool,
+}
+
+/// name and chn of the old key "eng-chn", and grants of the old value "role-bool:role-bool"
+fn parse_legacy_action(
+    page: &str,
+    key: &str,
+    value: &str,
+) -> WebhttpResult<(String, String, RpRoleGrants)> {
+    let malformed_key = |reason: &str| PermissionError::MalformedKey {
+        page: page.to_string(),
+        key: key.to_string(),
+        reason: reason.to_string(),
+    };
+    let key_split: Vec<&str> = key.split("-").collect();
+    let (eng, chn) =
+        match key_split[..] {
+            [eng, chn] => (eng.to_string(), chn.to_string()),
+            [_] => return Err(malformed_key("not splited by -, need eng and chn name").into()),
+            _ => return Err(malformed_key(
+                "length is not 2 after splitting by -, use the structured format for names with -",
+            )
+            .into()),
+        };
+
+    let mut grants = Vec::new();
+    for each in value.split(":") {
+        let malformed_value = |reason: String| PermissionError::MalformedValue {
+            page: page.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+            reason,
+        };
+        let each_split: Vec<&str> = each.split("-").collect();
+        let (role, status) = match each_split[..] {
+            [role, status] => (role, status),
+            [_] => {
+                return Err(malformed_value("not splited by -, need role and status".into()).into())
+            }
+            _ => return Err(malformed_value("length is not 2 after splitting by -".into()).into()),
+        };
+        let grant = RpRoleGrant::parse(status).ok_or_else(|| {
+            malformed_value(format!(
+                "status {} of role {} is not true, false or deny",
+                status, role
+            ))
+        })?;
+        grants.push((role.to_string(), grant));
+    }
+    Ok((eng, chn, grants))
+}
+
+fn parse_permission(permission: &RpInputPermission) -> WebhttpResult<Vec<RpParsedAction>> {
+    let mut pages: Vec<_> = permission.iter().collect();
+    pages.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut parsed = Vec::<RpParsedAction>::new();
+    for (page, input) in pages {
+        let mut page_actions = Vec::<RpParsedAction>::new();
+        match input {
+            RpInputPage::Map(actions) => {
+                for (key, value) in actions.iter() {
+                    let location = format!("permission.{}.{}", page, key);
+                    let action = match value {
+                        RpInputActionValue::Legacy(value) => {
+                            let (eng, chn, grants) = parse_legacy_action(page, key, value)?;
+                            RpParsedAction {
+                                page: page.clone(),
+                                name: eng,
+                                labels: BTreeMap::from([("zh".to_string(), chn)]),
+                                grants,
+                                location,
+                                legacy: true,
+                            }
+                        }
+                        RpInputActionValue::Action(action) => {
+                            if !action.name.is_empty() && !action.name.eq(key) {
+                                return Err(invalid(
+                                    format!("{}.name", location),
+                                    format!("name {} is different from its key", action.name),
+                                )
+                                .into());
+                            }
+                            RpParsedAction {
+                                page: page.clone(),
+                                name: key.clone(),
+                                labels: action.label.clone(),
+                                grants: action.grants(),
+                                location,
+                                legacy: false,
+                            }
+                        }
+                    };
+                    page_actions.push(action);
+                }
+            }
+            RpInputPage::List(actions) => {
+                for (index, action) in actions.iter().enumerate() {
+                    page_actions.push(RpParsedAction {
+                        page: page.clone(),
+                        name: action.name.clone(),
+                        labels: action.label.clone(),
+                        grants: action.grants(),
+                        location: format!("permission.{}[{}]", page, index),
+                        legacy: false,
+                    });
+                }
+            }
+        }
+
+        for (index, action) in page_actions.iter().enumerate() {
+            if action.name.is_empty() {
+                return Err(invalid(action.location.clone(), "name is missing".into()).into());
+            }
+            // names are also used as patterns of rules
+            if action.name.contains([':', '*']) {
+                return Err(invalid(
+                    action.location.clone(),
+                    format!("name {} can't contain : or *", action.name),
+                )
+                .into());
+            }
+            if page_actions[..index]
+                .iter()
+                .any(|v| v.name.eq(&action.name))
+            {
+                return Err(invalid(
+                    action.location.clone(),
+                    format!("action {} is duplicated in page {}", action.name, page),
+                )
+                .into());
+            }
+        }
+        parsed.extend(page_actions);
+    }
+    Ok(parsed)
+}
+
+impl RpConfig {
+    /// Same config in the structured format, chn names of the old format are `zh` labels.
+    /// Unknown roles of the old format are dropped, and the old keys which have the same
+    /// name in a page are rejected.
+    pub fn to_structured(&self) -> WebhttpResult<RpConfig> {
+        let mut permission = RpInputPermission::default();
+        for (page, input) in self.permission.iter() {
+            let page_input = match input {
+                RpInputPage::Map(actions) => {
+                    let mut converted = BTreeMap::new();
+                    for (key, value) in actions.iter() {
+                        let (name, action) = match value {
+                            RpInputActionValue::Legacy(value) => {
+                                let (eng, chn, grants) = parse_legacy_action(page, key, value)?;
+                                let grants = grants.into_iter().filter(|(role, _)| {
+                                    let known = self.role.contains_key(role);
+                                    if !known {
+                                        tracing::warn!(
+                                            "drop unknown role {} of permission.{}.{}",
+                                            role,
+                                            page,
+                                            key
+                                        );
+                                    }
+                                    known
+                                });
+                                let action = RpInputAction {
+                                    name: String::new(),
+                                    label: BTreeMap::from([("zh".to_string(), chn)]),
+                                    roles: RpInputRoles::Map(grants.into_iter().collect()),
+                                    deny: Vec::new(),
+                                };
+                                (eng, action)
+                            }
+                            RpInputActionValue::Action(action) => (key.clone(), action.clone()),
+                        };
+                        if converted.contains_key(&name) {
+                            return Err(invalid(
+                                format!("permission.{}.{}", page, key),
+                                format!("action {} is duplicated in page {}", name, page),
+                            )
+                            .into());
+                        }
+                        converted.insert(name, RpInputActionValue::Action(action));
+                    }
+                    RpInputPage::Map(converted)
+                }
+                RpInputPage::List(actions) => RpInputPage::List(actions.clone()),
+            };
+            permission.insert(page.clone(), page_input);
+        }
+        Ok(RpConfig {
+            permission,
+            ..self.clone()
+        })
+    }
+}
+
+/// Allow or deny of the matched actions, page and action are patterns
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RpRule {
+    pub page: String,
+    pub action: String,
+    pub allow: bool,
+}
+
+fn pattern_matches(pattern: &str, value: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => value.starts_with(prefix),
+        None => pattern.eq(value),
+    }
+}
+
+impl RpRule {
+    pub fn matches(&self, page: &str, action: &str) -> bool {
+        pattern_matches(&self.page, page) && pattern_matches(&self.action, action)
+    }
+
+    fn parse(role: &str, grant: &str) -> WebhttpResult<Self> {
+        let (allow, pattern) = match grant.strip_prefix('!') {
+            Some(pattern) => (false, pattern),
+            None => (true, grant),
+        };
+        let malformed = |reason: &str| PermissionError::MalformedGrant {
+            role: role.to_string(),
+            grant: grant.to_string(),
+            reason: reason.to_string(),
+        };
+        let (page, action) = pattern
+            .split_once(':')
+            .ok_or_else(|| malformed("not splited by :, need page and action"))?;
+        if page.is_empty() || action.is_empty() {
+            return Err(malformed("page or action is empty").into());
+        }
+        Ok(RpRule {
+            page: page.to_string(),
+            action: action.to_string(),
+            allow,
+        })
+    }
+}
+
+/// Allowed by any rule and not denied by any rule
+fn rules_allow<'a>(
+    rules: impl Iterator<Item = &'a RpRule> + Clone,
+    page: &str,
+    action: &str,
+) -> bool {
+    let mut matched = rules.filter(|v| v.matches(page, action));
+    matched.clone().any(|v| v.allow) && !matched.any(|v| !v.allow)
+}
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
 pub struct RpGroup {
     pub input_permission: RpInputPermission,
     pub input_role: RpInputRole,
-    // Kye is role name, value.0 is permision list, value.1 is account list
+    #[serde(default)]
+    pub input_inherit: RpInputInherit,
+    #[serde(default)]
+    pub input_grant: RpInputGrant,
+    // Kye is role name, value.0 is resolved permision list, value.1 is account list
     pub roles: HashMap<String, (RpAction, Vec<String>)>,
+    /// key is role name, value is the rules of role and the roles it inherits
+    #[serde(default)]
+    pub rules: HashMap<String, Vec<RpRule>>,
 }
 
 impl RpGroup {
@@ -56,161 +600,468 @@ impl RpGroup {
         role_map
     }
 
-    pub fn get_user_permission(&self, user_account: String) -> HashMap<String, RpAction> {
-        let mut user_permision = HashMap::<String, RpAction>::default();
-        for (each_role, (actions, users)) in self.roles.iter() {
-            if users.contains(&user_account) {
-                user_permision.insert(each_role.clone(), actions.clone());
+    /// Roles of the user, default role is always included
+    pub fn get_user_roles(&self, user_account: &str) -> Vec<String> {
+        let mut user_roles = Vec::<String>::default();
+        for (each_role, (_, users)) in self.roles.iter() {
+            if users.iter().any(|v| v.eq(user_account)) {
+                user_roles.push(each_role.clone());
             }
         }
-
-        if !user_permision.contains_key("default") {
-            if let Some(default_actions) = self.roles.get("default") {
-                user_permision.insert("default".into(), default_actions.0.clone());
+        if !user_roles.iter().any(|v| v.eq("default")) {
+            if self.roles.contains_key("default") {
+                user_roles.push("default".into());
             } else {
                 tracing::warn!("default permission is not existed");
             }
         }
+        user_roles
+    }
+
+    /// Effective permission of the user, which is resolved from all roles of the user with
+    /// inheritance, wildcard grants and denies. A deny of any role overrides the allows of
+    /// the other roles, so it agrees with check_user_action.
+    pub fn get_user_permission(&self, user_account: String) -> RpAction {
+        let user_roles = self.get_user_roles(&user_account);
+        let mut user_permision = RpAction::default();
+        for (page, items) in self.all_actions().actions {
+            let items = items
+                .into_iter()
+                .map(|item| RpItem {
+                    enabled: self.roles_allow(&user_roles, &page, &item.eng),
+                    ..item
+                })
+                .collect();
+            user_permision.actions.insert(page, items);
+        }
         user_permision
     }
 
-    fn check_user_action_internal(actions: RpAction, page: String, item: String) -> bool {
-        if let Some(action) = actions.actions.get(&page) {
-            for each in action.iter() {
-                if each.eng.eq(&item) && each.enabled {
-                    return true;
-                }
+    fn roles_allow(&self, roles: &[String], page: &str, action: &str) -> bool {
+        let rules = roles
+            .iter()
+            .filter_map(|v| self.rules.get(v))
+            .flat_map(|v| v.iter());
+        rules_allow(rules, page, action)
+    }
+
+    /// The action can also be one which is not in the permission table, if it's granted by
+    /// a wildcard
+    pub fn check_user_action(&self, user_account: String, page: String, action: String) -> bool {
+        let user_roles = self.get_user_roles(&user_account);
+        self.roles_allow(&user_roles, &page, &action)
+    }
+
+    /// All actions of the permission table, they are all disabled
+    fn all_actions(&self) -> RpAction {
+        let mut actions = RpAction::default();
+        if let Some((role_actions, _)) = self.roles.values().next() {
+            for (page, items) in role_actions.actions.iter() {
+                let items = items
+                    .iter()
+                    .map(|item| RpItem {
+                        enabled: false,
+                        ..item.clone()
+                    })
+                    .collect();
+                actions.actions.insert(page.clone(), items);
             }
         }
-        false
+        actions
     }
-    pub fn check_user_action(&self, user_account: String, page: String, action: String) -> bool {
-        let user_actions = self.get_user_permission(user_account.clone());
-        for (_role_tmp, role_action) in user_actions.iter() {
-            let check_status =
-                Self::check_user_action_internal(role_action.clone(), page.clone(), action.clone());
-            if check_status {
-                return true;
+
+    /// Parent roles must be existed and have no cycle, return the roles which are visited
+    /// in depth first order from the role
+    fn resolve_inherit(
+        inherit: &RpInputInherit,
+        role: &str,
+        path: &mut Vec<String>,
+        visited: &mut Vec<String>,
+    ) -> WebhttpResult<()> {
+        if let Some(index) = path.iter().position(|v| v.eq(role)) {
+            let mut cycle = path[index..].to_vec();
+            cycle.push(role.to_string());
+            return Err(PermissionError::InheritanceCycle {
+                cycle: cycle.join(" -> "),
             }
+            .into());
         }
-        false
+        if visited.iter().any(|v| v.eq(role)) {
+            return Ok(());
+        }
+        visited.push(role.to_string());
+        path.push(role.to_string());
+        for parent in inherit.get(role).into_iter().flatten() {
+            Self::resolve_inherit(inherit, parent, path, visited)?;
+        }
+        path.pop();
+        Ok(())
     }
 
-    pub fn create(config: RpConfig) -> anyhow::Result<Self> {
+    pub fn create(config: RpConfig) -> WebhttpResult<Self> {
         let role = config.role.clone();
         let permission = config.permission.clone();
         let mut role_permission = RpGroup::default();
         role_permission.input_permission = permission.clone();
         role_permission.input_role = role.clone();
+        role_permission.input_inherit = config.inherit.clone();
+        role_permission.input_grant = config.grant.clone();
 
-        for (each_role, role_users) in role.iter() {
-            let mut each_group_permisson = RpAction::default();
-            let mut role_internal_group_permission = RpAction::default();
-
-            for (each_group, action_map) in permission.iter() {
-                for (each_action, action_info) in action_map.iter() {
-                    if !each_action.contains("-") {
-                        return Err(anyhow::anyhow!(
-                            "action key is not splited by -, need eng and chn name: {}",
-                            each_action
-                        ));
-                    }
-                    let each_aciton_split: Vec<&str> = each_action.split("-").collect();
-                    if each_aciton_split.len() != 2 {
-                        return Err(anyhow::anyhow!(
-                            "action key length is not 2 after splitting by -: {}",
-                            each_action
-                        ));
-                    }
-                    let aciton_eng = each_aciton_split.get(0).unwrap().to_string();
-                    let aciton_chn = each_aciton_split.get(1).unwrap().to_string();
-
-                    let mut action_info_list = HashMap::<String, bool>::default();
-                    let action_info_split: Vec<&str> = action_info.split(":").collect();
-                    for action_info_each in action_info_split.iter() {
-                        let action_info_each = action_info_each.to_string();
-                        if !action_info_each.contains("-") {
-                            return Err(anyhow::anyhow!(
-                                "action role value is not splited by -, need role and status: {}",
-                                action_info
-                            ));
-                        }
-                        let action_info_each_split: Vec<&str> =
-                            action_info_each.split("-").collect();
-                        if action_info_each_split.len() != 2 {
-                            return Err(anyhow::anyhow!(
-                                "action role value length is not 2 after splitting by -: {}",
-                                action_info
-                            ));
-                        }
-
-                        let action_role = action_info_each_split.get(0).unwrap().to_string();
-                        let action_status = action_info_each_split.get(1).unwrap().to_string();
-                        let action_status = match action_status.as_str() {
-                            "true" => true,
-                            "false" => false,
-                            _ => false,
-                        };
-                        action_info_list.insert(action_role, action_status);
+        for (each_role, parents) in config.inherit.iter() {
+            if !role.contains_key(each_role) {
+                return Err(invalid(
+                    format!("inherit.{}", each_role),
+                    format!("role {} is not defined in [role]", each_role),
+                )
+                .into());
+            }
+            for parent in parents.iter() {
+                if !role.contains_key(parent) {
+                    return Err(PermissionError::UnknownParent {
+                        role: each_role.clone(),
+                        parent: parent.clone(),
                     }
+                    .into());
+                }
+            }
+        }
 
-                    let action_item = if action_info_list.contains_key(each_role) {
-                        RpItem {
-                            eng: aciton_eng.clone(),
-                            chn: aciton_chn.clone(),
-                            enabled: action_info_list.get(each_role).unwrap().clone(),
-                        }
-                    } else {
-                        RpItem {
-                            eng: aciton_eng.clone(),
-                            chn: aciton_chn.clone(),
-                            enabled: false,
-                        }
-                    };
-                    if each_group_permisson.actions.contains_key(each_group) {
-                        let key_index = each_group_permisson.actions.get_mut(each_group).unwrap();
-                        key_index.push(action_item);
-                    } else {
-                        each_group_permisson
-                            .actions
-                            .insert(each_group.clone(), vec![action_item]);
-                    }
+        // rules of every role itself, from the permission table and grants
+        let mut own_rules = HashMap::<String, Vec<RpRule>>::default();
+        for (each_role, grants) in config.grant.iter() {
+            if !role.contains_key(each_role) {
+                return Err(invalid(
+                    format!("grant.{}", each_role),
+                    format!("role {} is not defined in [role]", each_role),
+                )
+                .into());
+            }
+            for grant in grants.iter() {
+                own_rules
+                    .entry(each_role.clone())
+                    .or_default()
+                    .push(RpRule::parse(each_role, grant)?);
+            }
+        }
 
-                    if role_internal_group_permission
-                        .actions
-                        .contains_key(each_group)
-                    {
-                        let key_index = role_internal_group_permission
-                            .actions
-                            .get_mut(each_group)
-                            .unwrap();
-                        key_index.push(RpItem {
-                            eng: aciton_eng.clone(),
-                            chn: aciton_chn.clone(),
-                            enabled: false,
-                        });
-                    } else {
-                        role_internal_group_permission.actions.insert(
-                            each_group.clone(),
-                            vec![RpItem {
-                                eng: aciton_eng.clone(),
-                                chn: aciton_chn.clone(),
-                                enabled: false,
-                            }],
-                        );
-                    }
+        let mut all_actions = RpAction::default();
+        for action in parse_permission(&permission)? {
+            for (action_role, grant) in action.grants.iter() {
+                if !role.contains_key(action_role) && action.legacy {
+                    tracing::warn!(
+                        "role {} of {} is not defined in [role], it's ignored",
+                        action_role,
+                        action.location
+                    );
+                    continue;
+                }
+                if !role.contains_key(action_role) {
+                    return Err(invalid(
+                        format!("{}.roles.{}", action.location, action_role),
+                        format!("role {} is not defined in [role]", action_role),
+                    )
+                    .into());
                 }
+                let allow = match grant {
+                    RpRoleGrant::Allow => true,
+                    RpRoleGrant::Deny => false,
+                    RpRoleGrant::Unset => continue,
+                };
+                own_rules
+                    .entry(action_role.clone())
+                    .or_default()
+                    .push(RpRule {
+                        page: action.page.clone(),
+                        action: action.name.clone(),
+                        allow,
+                    });
+            }
+
+            all_actions
+                .actions
+                .entry(action.page.clone())
+                .or_default()
+                .push(RpItem {
+                    eng: action.name.clone(),
+                    chn: action.labels.get("zh").cloned().unwrap_or_default(),
+                    enabled: false,
+                    labels: action.labels.clone(),
+                });
+        }
+
+        for (each_role, role_users) in role.iter() {
+            let mut inherited = Vec::<String>::default();
+            Self::resolve_inherit(&config.inherit, each_role, &mut Vec::new(), &mut inherited)?;
+            let rules: Vec<RpRule> = inherited
+                .iter()
+                .filter_map(|v| own_rules.get(v))
+                .flatten()
+                .cloned()
+                .collect();
+
+            let mut each_group_permisson = RpAction::default();
+            for (page, items) in all_actions.actions.iter() {
+                let items = items
+                    .iter()
+                    .map(|item| RpItem {
+                        enabled: rules_allow(rules.iter(), page, &item.eng),
+                        ..item.clone()
+                    })
+                    .collect();
+                each_group_permisson.actions.insert(page.clone(), items);
             }
             role_permission.roles.insert(
                 each_role.clone(),
                 (each_group_permisson.clone(), role_users.clone()),
             );
-
-            // role_permission.roles.insert(
-            //     ROLE_INTERNAL.to_string(),
-            //     (role_internal_group_permission.clone(), vec![]),
-            // );
+            role_permission.rules.insert(each_role.clone(), rules);
         }
         Ok(role_permission)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(toml: &str) -> WebhttpResult<RpGroup> {
+        let config: RpConfig = toml::from_str(toml).unwrap();
+        RpGroup::create(config)
+    }
+
+    const CONFIG: &str = r#"
+        name = "testapp"
+
+        [role]
+        default = []
+        operator = ["alice"]
+        admin = ["root"]
+        auditor = ["bob", "root"]
+
+        [inherit]
+        admin = ["operator"]
+
+        [grant]
+        operator = ["user:*"]
+        admin = ["*:*", "!user:delete"]
+
+        [permission.user.user-read]
+        roles = { default = true, auditor = "deny" }
+
+        [permission.user.delete]
+        roles = { admin = true }
+
+        [permission.order.order-export]
+        roles = { operator = true }
+    "#;
+
+    #[test]
+    fn wildcard_grants() {
+        let group = group(CONFIG).unwrap();
+        let check = |user: &str, page: &str, action: &str| {
+            group.check_user_action(user.into(), page.into(), action.into())
+        };
+        assert!(check("alice", "user", "user-read"));
+        assert!(check("alice", "user", "not-in-table"));
+        assert!(!check("alice", "invoice", "read"));
+        // admin has *:* and the grants of operator
+        assert!(check("root", "invoice", "read"));
+        assert!(pattern_matches("user*", "user-read"));
+        assert!(!pattern_matches("user", "user-read"));
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let group = group(CONFIG).unwrap();
+        let check = |user: &str, page: &str, action: &str| {
+            group.check_user_action(user.into(), page.into(), action.into())
+        };
+        // denied by a grant, although it's allowed by the table and *:*
+        assert!(!check("root", "user", "delete"));
+        // denied by auditor, although admin allows every action
+        assert!(!check("root", "user", "user-read"));
+        assert!(!check("bob", "user", "user-read"));
+        assert!(check("alice", "user", "user-read"));
+    }
+
+    #[test]
+    fn user_permission_is_effective() {
+        let group = group(CONFIG).unwrap();
+        let enabled = |actions: &RpAction, page: &str, name: &str| {
+            actions.actions[page]
+                .iter()
+                .any(|v| v.eng.eq(name) && v.enabled)
+        };
+        let effective = group.get_user_permission("alice".into());
+        assert!(enabled(&effective, "user", "user-read"));
+        assert!(enabled(&effective, "order", "order-export"));
+        // user:* of operator
+        assert!(enabled(&effective, "user", "delete"));
+        // the deny of auditor also hides user-read which admin allows
+        let effective = group.get_user_permission("root".into());
+        assert!(!enabled(&effective, "user", "delete"));
+        assert!(!enabled(&effective, "user", "user-read"));
+        for (page, items) in effective.actions.iter() {
+            for item in items {
+                assert_eq!(
+                    item.enabled,
+                    group.check_user_action("root".into(), page.clone(), item.eng.clone())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn inheritance_cycle_is_rejected() {
+        let config = r#"
+            name = "testapp"
+            permission = {}
+
+            [role]
+            a = []
+            b = []
+            c = []
+
+            [inherit]
+            a = ["b"]
+            b = ["c"]
+            c = ["a"]
+        "#;
+        match group(config) {
+            Err(crate::error::WebhttpError::Permission(PermissionError::InheritanceCycle {
+                cycle,
+            })) => assert!(cycle.contains("->")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    const LEGACY: &str = r#"
+        name = "testapp"
+
+        [role]
+        default = []
+        admin = ["root"]
+
+        [permission.user]
+        "usermgt-用户管理" = "default-false:admin-true"
+        "userread-查看用户" = "default-true:admin-true:removed-true"
+    "#;
+
+    #[test]
+    fn legacy_format_is_converted() {
+        let config: RpConfig = toml::from_str(LEGACY).unwrap();
+        let legacy = RpGroup::create(config.clone()).unwrap();
+        let structured = config.to_structured().unwrap();
+        match &structured.permission["user"] {
+            RpInputPage::Map(actions) => {
+                let mut names: Vec<_> = actions.keys().cloned().collect();
+                names.sort();
+                assert_eq!(names, vec!["usermgt", "userread"]);
+                assert!(actions
+                    .values()
+                    .all(|v| matches!(v, RpInputActionValue::Action(_))));
+            }
+            RpInputPage::List(_) => panic!("page is converted to a list"),
+        }
+        let structured = RpGroup::create(structured).unwrap();
+        for user in ["root", "guest"] {
+            assert_eq!(
+                legacy.get_user_permission(user.into()),
+                structured.get_user_permission(user.into())
+            );
+        }
+        let item = &structured.get_user_permission("root".into()).actions["user"][0];
+        assert_eq!(item.label("zh"), item.chn);
+    }
+
+    #[test]
+    fn unknown_role_of_legacy_format_is_ignored() {
+        let config: RpConfig = toml::from_str(LEGACY).unwrap();
+        let group = RpGroup::create(config).unwrap();
+        assert!(group.check_user_action("guest".into(), "user".into(), "userread".into()));
+        assert!(!group.check_user_action("guest".into(), "user".into(), "usermgt".into()));
+    }
+
+    #[test]
+    fn duplicated_legacy_names_are_located() {
+        let config = r#"
+            name = "testapp"
+
+            [role]
+            default = []
+
+            [permission.user]
+            "a-x" = "default-true"
+            "a-y" = "default-false"
+        "#;
+        let config: RpConfig = toml::from_str(config).unwrap();
+        fn location<T>(result: WebhttpResult<T>) -> String {
+            match result {
+                Err(crate::error::WebhttpError::Permission(PermissionError::Invalid {
+                    location,
+                    ..
+                })) => location,
+                Err(e) => panic!("unexpected error: {:?}", e),
+                Ok(_) => panic!("duplicated names are accepted"),
+            }
+        }
+        assert_eq!(location(config.to_structured()), "permission.user.a-y");
+        assert_eq!(location(RpGroup::create(config)), "permission.user.a-y");
+    }
+
+    #[test]
+    fn errors_are_located() {
+        let config = r#"
+            name = "testapp"
+
+            [role]
+            default = []
+
+            [permission.user.read]
+            roles = { default = true, admin = true }
+        "#;
+        match group(config) {
+            Err(crate::error::WebhttpError::Permission(PermissionError::Invalid {
+                location,
+                ..
+            })) => assert_eq!(location, "permission.user.read.roles.admin"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let config = r#"
+            name = "testapp"
+
+            [role]
+            default = []
+
+            [permission.user]
+            "read" = "default-true"
+        "#;
+        assert!(matches!(
+            group(config),
+            Err(crate::error::WebhttpError::Permission(
+                PermissionError::MalformedKey { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn unknown_parent_is_rejected() {
+        let config = r#"
+            name = "testapp"
+            permission = {}
+
+            [role]
+            a = []
+
+            [inherit]
+            a = ["b"]
+        "#;
+        assert!(matches!(
+            group(config),
+            Err(crate::error::WebhttpError::Permission(
+                PermissionError::UnknownParent { .. }
+            ))
+        ));
+    }
+}
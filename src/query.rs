@@ -0,0 +1,169 @@
+use crate::response::{NoneBodyData, Response};
+use actix_web::{dev::Payload, error::InternalError, web, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAGE_SIZE: u64 = 20;
+pub const MAX_PAGE_SIZE: u64 = 200;
+/// deep offset pagination is slow, cursor pagination should be used instead
+pub const MAX_PAGE: u64 = 1_000_000;
+
+/// Query of list api, such as ?page=2&page_size=50 or ?cursor=xxx&page_size=50
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PageQuery {
+    #[serde(default = "default_page")]
+    pub page: u64, // starts from 1
+    #[serde(default = "default_page_size")]
+    pub page_size: u64,
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+fn default_page() -> u64 {
+    1
+}
+
+fn default_page_size() -> u64 {
+    DEFAULT_PAGE_SIZE
+}
+
+impl PageQuery {
+    pub fn offset(&self) -> u64 {
+        self.page.saturating_sub(1).saturating_mul(self.page_size)
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.page_size
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.page < 1 || self.page > MAX_PAGE {
+            return Err(format!("page must be in 1-{}, got {}", MAX_PAGE, self.page));
+        }
+        if self.page_size < 1 || self.page_size > MAX_PAGE_SIZE {
+            return Err(format!(
+                "page_size must be in 1-{}, got {}",
+                MAX_PAGE_SIZE, self.page_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Query of sorting, such as ?sort=created_at&order=desc
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SortQuery {
+    #[serde(default)]
+    pub sort: Option<String>,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+impl SortQuery {
+    /// only letters, digits, _ and . are allowed, so it can be used in sql safely
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(sort) = &self.sort {
+            let valid = !sort.is_empty()
+                && sort.len() <= 64
+                && sort
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+            if !valid {
+                return Err(format!("sort field is invalid: {}", sort));
+            }
+        }
+        Ok(())
+    }
+
+    /// check the sort field is one of the allowed fields
+    pub fn is_allowed(&self, fields: &[&str]) -> bool {
+        match &self.sort {
+            Some(sort) => fields.contains(&sort.as_str()),
+            None => true,
+        }
+    }
+}
+
+fn extract_query<T>(
+    req: &HttpRequest,
+    validate: impl Fn(&T) -> Result<(), String>,
+) -> Result<T, actix_web::Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let message = match web::Query::<T>::from_query(req.query_string()) {
+        Ok(query) => {
+            let query = query.into_inner();
+            match validate(&query) {
+                Ok(_) => return Ok(query),
+                Err(message) => message,
+            }
+        }
+        Err(e) => e.to_string(),
+    };
+    let rsp = Response::<NoneBodyData>::bad_request(&message)
+        .with_error_code("query_invalid")
+        .finished_status_for(req);
+    Err(InternalError::from_response(message, rsp).into())
+}
+
+impl FromRequest for PageQuery {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(extract_query(req, PageQuery::validate))
+    }
+}
+
+impl FromRequest for SortQuery {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(extract_query(req, SortQuery::validate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(page: u64, page_size: u64) -> PageQuery {
+        PageQuery {
+            page,
+            page_size,
+            cursor: None,
+        }
+    }
+
+    #[test]
+    fn offset_of_pages() {
+        assert_eq!(query(1, 20).offset(), 0);
+        assert_eq!(query(3, 20).offset(), 40);
+    }
+
+    #[test]
+    fn offset_never_overflows() {
+        assert_eq!(query(0, 20).offset(), 0);
+        assert_eq!(query(u64::MAX, MAX_PAGE_SIZE).offset(), u64::MAX);
+    }
+
+    #[test]
+    fn validate_page_range() {
+        assert!(query(1, 20).validate().is_ok());
+        assert!(query(MAX_PAGE, MAX_PAGE_SIZE).validate().is_ok());
+        assert!(query(0, 20).validate().is_err());
+        assert!(query(MAX_PAGE + 1, 20).validate().is_err());
+        assert!(query(1, 0).validate().is_err());
+        assert!(query(1, MAX_PAGE_SIZE + 1).validate().is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use crate::access_log::token_user_id;
+use crate::client_ip::client_ip;
+use crate::cors::path_in_scope;
+use crate::error::WebhttpResult;
+use crate::redis::select_redis;
+use crate::response::{NoneBodyData, Response};
+use crate::util::now_millis;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+use dashmap::DashMap;
+use fred::prelude::*;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const REDIS_PREFIX: &str = "webhttp:ratelimit";
+
+/// `[rate_limit]` of the config, see the example of WebOptions
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// the first matched rule is used, requests without matched rule are not limited
+    #[serde(default)]
+    pub rules: Vec<RateLimitRule>,
+    /// inbound message rate of each websocket session
+    #[serde(default)]
+    pub websocket: Option<WsRateLimit>,
+    /// share the buckets between instances by AppState.redis
+    #[serde(default)]
+    pub use_redis: bool,
+}
+
+/// Token bucket, `capacity` requests can be burst, and it's refilled in `per_seconds`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Quota {
+    pub capacity: u32,
+    pub per_seconds: u64,
+}
+
+impl Quota {
+    /// refilled tokens per millisecond
+    fn rate(&self) -> f64 {
+        self.capacity as f64 / (std::cmp::max(1, self.per_seconds) * 1000) as f64
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimitRule {
+    /// matches the route pattern, such as /api/v1/user/{id}, or the leading path segments
+    /// of request path
+    pub path: String,
+    /// empty means all methods
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+    #[serde(flatten)]
+    pub quota: Quota,
+}
+
+impl RateLimitRule {
+    fn matches(&self, req: &HttpRequest) -> bool {
+        let method_matched = self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|v| v.eq_ignore_ascii_case(req.method().as_str()));
+        let path_matched = req.match_pattern().is_some_and(|v| v.eq(&self.path))
+            || path_in_scope(&self.path, req.path());
+        method_matched && path_matched
+    }
+}
+
+/// Who is limited, anonymous request of User key is limited by its ip
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    User,
+    /// name of the function which is registered by rate_limit_key_fn
+    Custom(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WsRateLimit {
+    #[serde(flatten)]
+    pub quota: Quota,
+    /// close the session with policy violation, otherwise the message is dropped
+    #[serde(default)]
+    pub close_on_exceed: bool,
+}
+
+pub type RateLimitKeyFn = Arc<dyn Fn(&HttpRequest) -> Option<String> + Send + Sync>;
+
+lazy_static! {
+    static ref KEY_FNS: DashMap<String, RateLimitKeyFn> = DashMap::new();
+}
+
+/// Register custom key function, such as tenant id from header, None means not limited
+pub fn rate_limit_key_fn(
+    name: &str,
+    key_fn: impl Fn(&HttpRequest) -> Option<String> + Send + Sync + 'static,
+) {
+    KEY_FNS.insert(name.to_string(), Arc::new(key_fn));
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset_seconds: u64,       // until the bucket is full
+    pub retry_after: Option<u64>, // seconds, only for denied
+}
+
+impl RateLimitDecision {
+    fn new(quota: &Quota, allowed: bool, tokens: f64) -> Self {
+        let rate = quota.rate();
+        let to_seconds = |tokens: f64| (tokens / rate / 1000.0).ceil().max(0.0) as u64;
+        RateLimitDecision {
+            allowed,
+            limit: quota.capacity,
+            remaining: tokens.floor().max(0.0) as u32,
+            reset_seconds: to_seconds(quota.capacity as f64 - tokens),
+            retry_after: if allowed {
+                None
+            } else {
+                Some(std::cmp::max(1, to_seconds(1.0 - tokens)))
+            },
+        }
+    }
+
+    /// RateLimit-Limit, RateLimit-Remaining, RateLimit-Reset and Retry-After
+    pub fn write_headers(&self, headers: &mut HeaderMap) {
+        let mut values = vec![
+            ("ratelimit-limit", self.limit as u64),
+            ("ratelimit-remaining", self.remaining as u64),
+            ("ratelimit-reset", self.reset_seconds),
+        ];
+        if let Some(retry_after) = self.retry_after {
+            values.push(("retry-after", retry_after));
+        }
+        for (name, value) in values {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+}
+
+/// In-process token bucket, it's also used by websocket session directly
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    quota: Quota,
+    tokens: f64,
+    updated: u64, // milliseconds
+}
+
+impl TokenBucket {
+    pub fn new(quota: &Quota) -> Self {
+        TokenBucket {
+            quota: quota.clone(),
+            tokens: quota.capacity as f64,
+            updated: now_millis(),
+        }
+    }
+
+    pub fn take(&mut self) -> RateLimitDecision {
+        let now = now_millis();
+        let elapsed = now.saturating_sub(self.updated) as f64;
+        self.tokens = (self.tokens + elapsed * self.quota.rate()).min(self.quota.capacity as f64);
+        self.updated = now;
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+        RateLimitDecision::new(&self.quota, allowed, self.tokens)
+    }
+
+    fn is_full(&self) -> bool {
+        let elapsed = now_millis().saturating_sub(self.updated) as f64;
+        self.tokens + elapsed * self.quota.rate() >= self.quota.capacity as f64
+    }
+}
+
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or capacity
+local updated = tonumber(state[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - updated) * rate)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], ARGV[4])
+return {allowed, tostring(tokens)}
+"#;
+
+pub struct RateLimiter {
+    pub config: RateLimitConfig,
+    redis: Option<RedisPool>,
+    buckets: DashMap<String, TokenBucket>,
+}
+
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::<RateLimiter>::new();
+
+pub fn rate_limit_init(config: RateLimitConfig, redis: Option<RedisPool>) -> &'static RateLimiter {
+    RATE_LIMITER.get_or_init(|| {
+        let redis = select_redis(config.use_redis, redis, "rate limit");
+        let limiter = RateLimiter {
+            config,
+            redis,
+            buckets: DashMap::new(),
+        };
+        if limiter.redis.is_none() {
+            start_cleaner();
+        }
+        info!("rate limit is enabled: {:?}", limiter.config);
+        limiter
+    })
+}
+
+/// return None when rate limit is not enabled
+pub fn rate_limit_get() -> Option<&'static RateLimiter> {
+    RATE_LIMITER.get()
+}
+
+/// full buckets are same as missing ones, so they are released
+fn start_cleaner() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            if let Some(limiter) = rate_limit_get() {
+                limiter.buckets.retain(|_, bucket| !bucket.is_full());
+            }
+        }
+    });
+}
+
+impl RateLimiter {
+    /// Take one token from the bucket of key, it can also be used by handlers directly.
+    /// Redis failure is logged and the request is allowed.
+    pub async fn check(&self, key: &str, quota: &Quota) -> RateLimitDecision {
+        if let Some(redis) = &self.redis {
+            match self.check_redis(redis, key, quota).await {
+                Ok(decision) => return decision,
+                Err(e) => {
+                    warn!("check rate limit in redis with error: {:?}", e);
+                    return RateLimitDecision::new(quota, true, quota.capacity as f64);
+                }
+            }
+        }
+        self.buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket::new(quota))
+            .take()
+    }
+
+    async fn check_redis(
+        &self,
+        redis: &RedisPool,
+        key: &str,
+        quota: &Quota,
+    ) -> WebhttpResult<RateLimitDecision> {
+        let ttl = std::cmp::max(1, quota.per_seconds) * 1000;
+        let (allowed, tokens): (i64, String) = redis
+            .eval(
+                TOKEN_BUCKET_SCRIPT,
+                vec![format!("{}:{}", REDIS_PREFIX, key)],
+                vec![
+                    quota.capacity.to_string(),
+                    quota.rate().to_string(),
+                    now_millis().to_string(),
+                    ttl.to_string(),
+                ],
+            )
+            .await?;
+        let tokens = tokens.parse::<f64>().unwrap_or_default();
+        Ok(RateLimitDecision::new(quota, allowed == 1, tokens))
+    }
+
+    /// None if no rule is matched or the key is not available
+    pub async fn check_request(&self, req: &HttpRequest) -> Option<RateLimitDecision> {
+        let (index, rule) = self
+            .config
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(req))?;
+        let ip = || client_ip(req).map(|v| format!("ip:{}", v));
+        let key = match &rule.key {
+            RateLimitKey::Ip => ip(),
+            RateLimitKey::User => match token_user_id(req) {
+                Some(user_id) => Some(format!("user:{}", user_id)),
+                None => ip(),
+            },
+            RateLimitKey::Custom(name) => match KEY_FNS.get(name) {
+                Some(key_fn) => key_fn(req).map(|v| format!("{}:{}", name, v)),
+                None => {
+                    warn!("rate limit key function is not registered: {}", name);
+                    None
+                }
+            },
+        }?;
+        Some(self.check(&format!("{}:{}", index, key), &rule.quota).await)
+    }
+}
+
+/// App level middleware which applies the rules of RateLimitConfig
+pub struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let decision = match rate_limit_get() {
+                Some(limiter) => limiter.check_request(req.request()).await,
+                None => None,
+            };
+            let decision = match decision {
+                Some(decision) => decision,
+                None => return Ok(service.call(req).await?.map_into_left_body()),
+            };
+            if !decision.allowed {
+                warn!(
+                    "{} {} is rate limited, retry after {:?}s",
+                    req.method(),
+                    req.path(),
+                    decision.retry_after
+                );
+                let mut rsp = Response::<NoneBodyData>::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "too many requests",
+                    None,
+                )
+                .with_error_code("rate_limited")
+                .finished_status_for(req.request());
+                decision.write_headers(rsp.headers_mut());
+                return Ok(req.into_response(rsp).map_into_right_body());
+            }
+            let mut rsp = service.call(req).await?;
+            decision.write_headers(rsp.headers_mut());
+            Ok(rsp.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn rule(path: &str, methods: &[&str]) -> RateLimitRule {
+        RateLimitRule {
+            path: path.to_string(),
+            methods: methods.iter().map(|v| v.to_string()).collect(),
+            key: RateLimitKey::Ip,
+            quota: Quota {
+                capacity: 5,
+                per_seconds: 60,
+            },
+        }
+    }
+
+    #[test]
+    fn rule_matches_whole_segments() {
+        let login = rule("/api/login", &["POST"]);
+        assert!(login.matches(&TestRequest::post().uri("/api/login").to_http_request()));
+        assert!(!login.matches(&TestRequest::post().uri("/api/loginx").to_http_request()));
+        assert!(!login.matches(&TestRequest::get().uri("/api/login").to_http_request()));
+
+        let api = rule("/api/", &[]);
+        assert!(api.matches(&TestRequest::get().uri("/api/v1/users").to_http_request()));
+        assert!(!api.matches(&TestRequest::get().uri("/apikeys").to_http_request()));
+    }
+}
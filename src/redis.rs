@@ -3,7 +3,7 @@ use fred::{
     types::{PerformanceConfig, RedisConfig},
 };
 use std::sync::OnceLock;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 static REDISPOOL: OnceLock<RedisPool> = OnceLock::<RedisPool>::new();
 
@@ -46,3 +46,32 @@ pub fn init_redis_pool(
 pub fn get_redis_pool() -> &'static RedisPool {
     REDISPOOL.get().unwrap()
 }
+
+/// the redis of a store configured with use_redis, None means the store keeps in memory
+pub(crate) fn select_redis(
+    use_redis: bool,
+    redis: Option<RedisPool>,
+    name: &str,
+) -> Option<RedisPool> {
+    if !use_redis {
+        return None;
+    }
+    if redis.is_none() {
+        warn!(
+            "{} is configured to use redis, but redis is null, use memory",
+            name
+        );
+    }
+    redis
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_redis_falls_back_to_memory() {
+        assert!(select_redis(false, None, "test store").is_none());
+        assert!(select_redis(true, None, "test store").is_none());
+    }
+}
@@ -0,0 +1,423 @@
+use crate::access_token::{token_config, AccessToken, TokenConfig, TokenError, TokenType};
+use crate::auth_cookie::{request_refresh_token, set_token_cookies, CookieSession};
+use crate::error::{WebhttpError, WebhttpResult};
+use crate::keyset::{keyset_get, KeySet};
+use crate::redis::select_redis;
+use crate::response::{NoneBodyData, Response};
+use crate::revocation::revocation_get;
+use actix_web::{web, HttpRequest, HttpResponse};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use fred::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const REDIS_PREFIX: &str = "webhttp:refresh";
+const REVOKED: &str = "!revoked";
+
+/// Compare the presented jti with the current one of family and replace it atomically, the
+/// family is started by its first token if ARGV[5] is 1.
+/// 1: rotated, 2: reused and the family is revoked now, 3: already revoked, 0: unknown
+const ROTATE_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if not current then
+    if ARGV[5] == '1' then
+        redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[4])
+        return 1
+    end
+    return 0
+end
+if current == ARGV[3] then
+    return 3
+end
+if current ~= ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[3], 'EX', ARGV[4])
+    return 2
+end
+redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[4])
+return 1
+"#;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    Rotated,
+    /// an old refresh token is presented again, the whole family is revoked
+    Reused,
+    Revoked,
+    /// the family is expired or never issued
+    Unknown,
+}
+
+/// State of one family, current is None after the family is revoked
+struct FamilyState {
+    current: Option<String>,
+    expires_at: Instant,
+}
+
+/// Refresh tokens of one login are in a family, and only the latest one is usable
+///
+/// Every refreshing rotates the refresh token. If a rotated token is presented again, the
+/// token may be stolen, so the whole family is revoked and both the thief and the user have
+/// to login again.
+pub struct RefreshStore {
+    lifetime: Duration,
+    redis: Option<RedisPool>,
+    families: DashMap<String, FamilyState>,
+}
+
+static REFRESH: OnceLock<RefreshStore> = OnceLock::<RefreshStore>::new();
+
+pub fn refresh_init(config: &TokenConfig, redis: Option<RedisPool>) -> &'static RefreshStore {
+    REFRESH.get_or_init(|| {
+        let redis = select_redis(config.refresh_use_redis, redis, "refresh store");
+        let store = RefreshStore {
+            lifetime: Duration::from_secs(config.refresh_lifetime),
+            redis,
+            families: DashMap::new(),
+        };
+        if store.redis.is_none() {
+            start_cleaner();
+        }
+        info!(
+            "refresh token store is enabled, use redis: {}",
+            store.redis.is_some()
+        );
+        store
+    })
+}
+
+/// return None when the server is not started by webhttp
+pub fn refresh_get() -> Option<&'static RefreshStore> {
+    REFRESH.get()
+}
+
+fn start_cleaner() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            if let Some(store) = refresh_get() {
+                let now = Instant::now();
+                store.families.retain(|_, state| state.expires_at > now);
+            }
+        }
+    });
+}
+
+impl RefreshStore {
+    fn family_key(family: &str) -> String {
+        format!("{}:{}", REDIS_PREFIX, family)
+    }
+
+    /// Start a family with its first refresh token
+    pub async fn issue(&self, family: &str, jti: &str) -> WebhttpResult<()> {
+        if let Some(redis) = &self.redis {
+            let _: () = redis
+                .set(
+                    Self::family_key(family),
+                    jti,
+                    Some(Expiration::EX(self.lifetime.as_secs() as i64)),
+                    None,
+                    false,
+                )
+                .await?;
+            return Ok(());
+        }
+        self.families.insert(
+            family.to_string(),
+            FamilyState {
+                current: Some(jti.to_string()),
+                expires_at: Instant::now() + self.lifetime,
+            },
+        );
+        Ok(())
+    }
+
+    /// Replace the presented refresh token by the new one, the family lives for another
+    /// refresh lifetime
+    pub async fn rotate(
+        &self,
+        family: &str,
+        presented: &str,
+        next: &str,
+    ) -> WebhttpResult<Rotation> {
+        // the first token of a family which is not issued by the store, see create_refresh_token
+        let start = family.eq(presented);
+        if let Some(redis) = &self.redis {
+            let result: i64 = redis
+                .eval(
+                    ROTATE_SCRIPT,
+                    vec![Self::family_key(family)],
+                    vec![
+                        presented.to_string(),
+                        next.to_string(),
+                        REVOKED.to_string(),
+                        self.lifetime.as_secs().to_string(),
+                        (start as u8).to_string(),
+                    ],
+                )
+                .await?;
+            return Ok(match result {
+                1 => Rotation::Rotated,
+                2 => Rotation::Reused,
+                3 => Rotation::Revoked,
+                _ => Rotation::Unknown,
+            });
+        }
+
+        let mut state = match self.families.entry(family.to_string()) {
+            Entry::Occupied(state) if state.get().expires_at > Instant::now() => state.into_ref(),
+            Entry::Occupied(state) if start => {
+                let mut state = state.into_ref();
+                state.current = Some(presented.to_string());
+                state
+            }
+            Entry::Vacant(state) if start => state.insert(FamilyState {
+                current: Some(presented.to_string()),
+                expires_at: Instant::now(),
+            }),
+            _ => return Ok(Rotation::Unknown),
+        };
+        state.expires_at = Instant::now() + self.lifetime;
+        match state.current.as_deref() {
+            None => Ok(Rotation::Revoked),
+            Some(current) if current.eq(presented) => {
+                state.current = Some(next.to_string());
+                Ok(Rotation::Rotated)
+            }
+            Some(_) => {
+                state.current = None;
+                Ok(Rotation::Reused)
+            }
+        }
+    }
+
+    /// Revoke a family, such as logout, its refresh tokens can't be used any more
+    pub async fn revoke_family(&self, family: &str) -> WebhttpResult<()> {
+        if let Some(redis) = &self.redis {
+            let _: () = redis
+                .set(
+                    Self::family_key(family),
+                    REVOKED,
+                    Some(Expiration::EX(self.lifetime.as_secs() as i64)),
+                    None,
+                    false,
+                )
+                .await?;
+            return Ok(());
+        }
+        self.families.insert(
+            family.to_string(),
+            FamilyState {
+                current: None,
+                expires_at: Instant::now() + self.lifetime,
+            },
+        );
+        Ok(())
+    }
+
+    pub async fn is_family_revoked(&self, family: &str) -> WebhttpResult<bool> {
+        if let Some(redis) = &self.redis {
+            let current: Option<String> = redis.get(Self::family_key(family)).await?;
+            return Ok(current.as_deref() == Some(REVOKED));
+        }
+        Ok(self
+            .families
+            .get(family)
+            .is_some_and(|state| state.current.is_none()))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    /// seconds of access token
+    pub expires_in: u64,
+    pub refresh_expires_in: u64,
+}
+
+/// Sign the access and refresh tokens of family from the claims, return the jti of refresh
+fn sign_pair(
+    claims: &AccessToken,
+    family: &str,
+    keys: &KeySet,
+) -> WebhttpResult<(TokenPair, String)> {
+    let config = token_config();
+    let mut access = AccessToken::new(
+        claims.user_id,
+        &claims.user_account,
+        &claims.user_name,
+        &claims.app_id,
+        Duration::from_secs(config.access_lifetime),
+    );
+    access.roles = claims.roles.clone();
+    access.scopes = claims.scopes.clone();
+    access.extra = claims.extra.clone();
+    access.family = Some(family.to_string());
+    access.auth_time = claims.auth_time.or(Some(access.iat));
+
+    let mut refresh = access.clone();
+    refresh.jti = uuid::Uuid::new_v4().simple().to_string();
+    refresh.exp = refresh.iat + config.refresh_lifetime;
+    refresh.token_type = TokenType::Refresh;
+
+    let pair = TokenPair {
+        access_token: access.sign(keys)?,
+        refresh_token: refresh.sign(keys)?,
+        token_type: "Bearer".into(),
+        expires_in: config.access_lifetime,
+        refresh_expires_in: config.refresh_lifetime,
+    };
+    Ok((pair, refresh.jti))
+}
+
+fn refresh_context() -> WebhttpResult<(&'static RefreshStore, &'static KeySet)> {
+    let store = refresh_get()
+        .ok_or_else(|| WebhttpError::Config("refresh token store is not initialized".into()))?;
+    let keys =
+        keyset_get().ok_or_else(|| WebhttpError::Config("jwt keys are not configured".into()))?;
+    Ok((store, keys))
+}
+
+/// Issue the tokens of a new login, user_id, roles, scopes and custom claims are taken from
+/// the claims and kept by every refreshing
+pub async fn issue_token_pair(claims: &AccessToken) -> WebhttpResult<TokenPair> {
+    let (store, keys) = refresh_context()?;
+    let family = uuid::Uuid::new_v4().simple().to_string();
+    let (pair, jti) = sign_pair(claims, &family, keys)?;
+    store.issue(&family, &jti).await?;
+    Ok(pair)
+}
+
+/// Rotate the refresh token, and issue a new access token
+pub async fn refresh_token_pair(refresh_token: &str) -> WebhttpResult<TokenPair> {
+    let (store, keys) = refresh_context()?;
+    let claims = AccessToken::decode_refresh_with_keys(refresh_token, keys)?;
+    let family = claims.family.clone().ok_or(TokenError::Invalid {
+        reason: "refresh token has no family".into(),
+    })?;
+    // the user may be revoked after the login
+    if let Some(revocation) = revocation_get() {
+        revocation.check(&claims).await?;
+    }
+    let (pair, jti) = sign_pair(&claims, &family, keys)?;
+    match store.rotate(&family, &claims.jti, &jti).await? {
+        Rotation::Rotated => Ok(pair),
+        Rotation::Reused => {
+            warn!(
+                "refresh token is reused, revoke family {} of user {}",
+                family, claims.user_id
+            );
+            Err(TokenError::Reused.into())
+        }
+        Rotation::Revoked | Rotation::Unknown => Err(TokenError::Revoked.into()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Mounted under api_prefix when `token.refresh_path` is configured. The refresh token is
+/// taken from the body, or the cookie if cookie authentication is enabled, and the tokens of
+/// cookie are only returned by cookies.
+pub async fn refresh_handler(
+    req: HttpRequest,
+    body: Option<web::Json<RefreshRequest>>,
+) -> HttpResponse {
+    let (refresh_token, by_cookie) = match body {
+        Some(body) => (Some(body.into_inner().refresh_token), false),
+        None => (request_refresh_token(&req), true),
+    };
+    let refresh_token = match refresh_token {
+        Some(refresh_token) => refresh_token,
+        None => {
+            return Response::<NoneBodyData>::from(WebhttpError::from(TokenError::Missing))
+                .finished_for(&req)
+        }
+    };
+    match refresh_token_pair(&refresh_token).await {
+        Ok(pair) if by_cookie => {
+            let mut rsp = Response::success(CookieSession::from(&pair)).finished_for(&req);
+            set_token_cookies(&mut rsp, &pair);
+            rsp
+        }
+        Ok(pair) => Response::success(pair).finished_for(&req),
+        Err(e) => Response::<NoneBodyData>::from(e).finished_for(&req),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn store() -> RefreshStore {
+        RefreshStore {
+            lifetime: Duration::from_secs(60),
+            redis: None,
+            families: DashMap::new(),
+        }
+    }
+
+    #[test]
+    fn rotate_and_detect_reuse() {
+        let store = store();
+        block_on(async {
+            store.issue("f", "1").await.unwrap();
+            assert_eq!(
+                store.rotate("f", "1", "2").await.unwrap(),
+                Rotation::Rotated
+            );
+            assert_eq!(store.rotate("f", "1", "3").await.unwrap(), Rotation::Reused);
+            assert_eq!(
+                store.rotate("f", "2", "3").await.unwrap(),
+                Rotation::Revoked
+            );
+            assert!(store.is_family_revoked("f").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn unknown_family_is_rejected() {
+        let store = store();
+        block_on(async {
+            assert_eq!(
+                store.rotate("f", "1", "2").await.unwrap(),
+                Rotation::Unknown
+            );
+        });
+    }
+
+    #[test]
+    fn family_is_started_by_first_token() {
+        let store = store();
+        block_on(async {
+            assert_eq!(
+                store.rotate("f", "f", "2").await.unwrap(),
+                Rotation::Rotated
+            );
+            assert_eq!(store.rotate("f", "f", "3").await.unwrap(), Rotation::Reused);
+            assert_eq!(
+                store.rotate("f", "2", "3").await.unwrap(),
+                Rotation::Revoked
+            );
+        });
+    }
+
+    #[test]
+    fn revoked_family_is_not_started_again() {
+        let store = store();
+        block_on(async {
+            store.revoke_family("f").await.unwrap();
+            assert_eq!(
+                store.rotate("f", "f", "2").await.unwrap(),
+                Rotation::Revoked
+            );
+        });
+    }
+}
@@ -1,6 +1,27 @@
-use actix_web::{http::StatusCode, HttpResponse};
+use crate::access_log;
+use crate::options::{ErrorFormat, ResponseConfig};
+use crate::query::PageQuery;
+use actix_web::dev::ServiceResponse;
+use actix_web::error::{
+    InternalError, JsonPayloadError, PathError, QueryPayloadError, UrlencodedError,
+};
+use actix_web::http::header;
+use actix_web::middleware::ErrorHandlerResponse;
+use actix_web::{body::BoxBody, http::StatusCode, HttpRequest, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use std::sync::OnceLock;
+use tracing::warn;
+
+static RESPONSE_CONFIG: OnceLock<ResponseConfig> = OnceLock::<ResponseConfig>::new();
+
+pub fn response_init(config: ResponseConfig) -> &'static ResponseConfig {
+    RESPONSE_CONFIG.get_or_init(|| config)
+}
+
+pub fn response_config() -> ResponseConfig {
+    RESPONSE_CONFIG.get().cloned().unwrap_or_default()
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct NoneBodyData {}
@@ -10,6 +31,9 @@ pub struct Response<T: Serialize + Debug> {
     pub code: StatusCode,
     pub message: String,
     pub data: Option<T>,
+    pub error_code: Option<String>, // application specific error code
+    pub request_id: Option<String>,
+    pub details: Option<serde_json::Value>, // structured error details
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -30,6 +54,8 @@ pub struct OkData<T: Serialize + Debug> {
     pub status: bool,
     pub code: u16,
     pub message: T,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -37,89 +63,345 @@ pub struct ErrData {
     pub status: bool,
     pub code: u16,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+/// Standard shape of list, `next_cursor` is used by cursor pagination
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<u64>,
+    pub page_size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// page of offset pagination
+    pub fn new(items: Vec<T>, total: u64, query: &PageQuery) -> Self {
+        Page {
+            items,
+            total: Some(total),
+            page: Some(query.page),
+            page_size: query.page_size,
+            next_cursor: None,
+        }
+    }
+
+    /// page of cursor pagination, next_cursor is None if it's the last page
+    pub fn with_cursor(items: Vec<T>, query: &PageQuery, next_cursor: Option<String>) -> Self {
+        Page {
+            items,
+            total: None,
+            page: None,
+            page_size: query.page_size,
+            next_cursor,
+        }
+    }
+}
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// RFC 7807 problem details document
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(flatten)]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
 }
 
 impl<T: Serialize + Debug> Response<T> {
-    #[allow(dead_code)]
-    pub fn success(data: T) -> Self {
+    pub fn new(code: StatusCode, message: &str, data: Option<T>) -> Self {
         Self {
-            code: StatusCode::OK,
-            message: "".to_string(),
-            data: Some(data),
+            code,
+            message: message.to_string(),
+            data,
+            error_code: None,
+            request_id: None,
+            details: None,
         }
     }
 
+    #[allow(dead_code)]
+    pub fn success(data: T) -> Self {
+        Self::new(StatusCode::OK, "", Some(data))
+    }
+
+    pub fn created(data: T) -> Self {
+        Self::new(StatusCode::CREATED, "", Some(data))
+    }
+
+    pub fn accepted(data: T) -> Self {
+        Self::new(StatusCode::ACCEPTED, "", Some(data))
+    }
+
+    pub fn no_content() -> Self {
+        Self::new(StatusCode::NO_CONTENT, "", None)
+    }
+
     #[allow(dead_code)]
     pub fn internal_error(message: &str) -> Self {
-        Self {
-            code: StatusCode::INTERNAL_SERVER_ERROR,
-            message: message.to_string(),
-            data: None,
-        }
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message, None)
     }
 
     #[allow(dead_code)]
     pub fn bad_request(message: &str) -> Self {
-        Self {
-            code: StatusCode::BAD_REQUEST,
-            message: message.to_string(),
-            data: None,
-        }
+        Self::new(StatusCode::BAD_REQUEST, message, None)
     }
 
     #[allow(dead_code)]
     pub fn forbidden(message: &str) -> Self {
-        Self {
-            code: StatusCode::FORBIDDEN,
-            message: message.to_string(),
-            data: None,
-        }
+        Self::new(StatusCode::FORBIDDEN, message, None)
     }
 
     #[allow(dead_code)]
     pub fn not_acceptable(message: &str) -> Self {
-        Self {
-            code: StatusCode::NOT_ACCEPTABLE,
-            message: message.to_string(),
-            data: None,
-        }
+        Self::new(StatusCode::NOT_ACCEPTABLE, message, None)
     }
 
     #[allow(dead_code)]
     pub fn unauthorized(message: &str) -> Self {
-        Self {
-            code: StatusCode::UNAUTHORIZED,
-            message: message.to_string(),
-            data: None,
-        }
+        Self::new(StatusCode::UNAUTHORIZED, message, None)
     }
 
     #[allow(dead_code)]
     pub fn nofound(message: &str) -> Self {
-        Self {
-            code: StatusCode::NOT_FOUND,
-            message: message.to_string(),
-            data: None,
-        }
+        Self::new(StatusCode::NOT_FOUND, message, None)
+    }
+
+    pub fn with_error_code(mut self, error_code: &str) -> Self {
+        self.error_code = Some(error_code.to_string());
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+
+    pub fn with_details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
     }
 
     pub fn finished(&self) -> HttpResponse {
+        let config = response_config();
+        if !self.code.is_success() && config.error_format == ErrorFormat::Problem {
+            return self.problem_response(None, self.request_id.clone());
+        }
+        self.envelope_response(config.http_status, self.request_id.clone())
+    }
+
+    /// Same as finished, and the error format can be chosen by Accept header of request,
+    /// request_id is taken from the request if it's not set
+    pub fn finished_for(&self, req: &HttpRequest) -> HttpResponse {
+        self.render_for(req, response_config().http_status)
+    }
+
+    /// Same as finished_for, but the HTTP status is always the real one whatever
+    /// `response.http_status` is, it's used by the errors which clients and proxies
+    /// already rely on, such as 404 of unknown path
+    pub fn finished_status_for(&self, req: &HttpRequest) -> HttpResponse {
+        self.render_for(req, true)
+    }
+
+    fn render_for(&self, req: &HttpRequest, http_status: bool) -> HttpResponse {
+        let config = response_config();
+        let request_id = self
+            .request_id
+            .clone()
+            .or_else(|| access_log::request_id(req));
+        if !self.code.is_success() {
+            let use_problem = match config.error_format {
+                ErrorFormat::Envelope => false,
+                ErrorFormat::Problem => true,
+                ErrorFormat::Negotiate => accept_problem(req),
+            };
+            if use_problem {
+                return self.problem_response(Some(req.path()), request_id);
+            }
+        }
+        self.envelope_response(http_status, request_id)
+    }
+
+    pub fn problem(&self, instance: Option<&str>) -> Problem {
+        self.problem_with(instance, self.request_id.clone())
+    }
+
+    fn problem_with(&self, instance: Option<&str>, request_id: Option<String>) -> Problem {
+        let type_uri = match (&response_config().problem_type_base, &self.error_code) {
+            (Some(base), Some(error_code)) => format!("{}{}", base, error_code),
+            _ => "about:blank".to_string(),
+        };
+        let mut extensions = serde_json::Map::new();
+        if let Some(error_code) = &self.error_code {
+            extensions.insert("error_code".into(), error_code.clone().into());
+        }
+        if let Some(request_id) = request_id {
+            extensions.insert("request_id".into(), request_id.into());
+        }
+        if let Some(details) = &self.details {
+            extensions.insert("details".into(), details.clone());
+        }
+        Problem {
+            type_uri,
+            title: self
+                .code
+                .canonical_reason()
+                .unwrap_or("Unknown Error")
+                .to_string(),
+            status: self.code.as_u16(),
+            detail: if self.message.is_empty() {
+                None
+            } else {
+                Some(self.message.clone())
+            },
+            instance: instance.map(|v| v.to_string()),
+            extensions,
+        }
+    }
+
+    fn problem_response(&self, instance: Option<&str>, request_id: Option<String>) -> HttpResponse {
+        let body =
+            serde_json::to_string(&self.problem_with(instance, request_id)).unwrap_or_default();
+        HttpResponse::build(self.code)
+            .content_type(PROBLEM_CONTENT_TYPE)
+            .body(body)
+    }
+
+    fn envelope_response(&self, http_status: bool, request_id: Option<String>) -> HttpResponse {
+        // 204 can't have body
+        if http_status && self.code == StatusCode::NO_CONTENT {
+            return HttpResponse::NoContent().finish();
+        }
+        let mut builder = if http_status {
+            HttpResponse::build(self.code)
+        } else {
+            HttpResponse::Ok()
+        };
         if self.code.is_success() {
-            HttpResponse::Ok().json(serde_json::json!(OkData {
+            builder.json(serde_json::json!(OkData {
                 status: true,
                 code: self.code.as_u16(),
-                message: self.data.as_ref().unwrap(),
+                message: self.data.as_ref(),
+                request_id,
             }))
         } else {
-            HttpResponse::Ok().json(serde_json::json!(ErrData {
+            builder.json(serde_json::json!(ErrData {
                 status: false,
                 code: self.code.as_u16(),
                 message: self.message.clone(),
+                error_code: self.error_code.clone(),
+                request_id,
+                details: self.details.clone(),
             }))
         }
     }
 }
 
+fn accept_problem(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains(PROBLEM_CONTENT_TYPE))
+}
+
+/// Error code of the response which is not created by Response, such as payload_too_large
+pub fn default_error_code(status: StatusCode) -> String {
+    status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_lowercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+}
+
+fn extractor_error<E>(err: E, req: &HttpRequest, error_code: &str) -> actix_web::Error
+where
+    E: actix_web::ResponseError + 'static,
+{
+    let status = err.status_code();
+    warn!(
+        "{} {} extract request with error: {}",
+        req.method(),
+        req.path(),
+        err
+    );
+    // framework errors always keep their real status
+    let rsp = Response::<NoneBodyData>::new(status, &err.to_string(), None)
+        .with_error_code(error_code)
+        .finished_status_for(req);
+    InternalError::from_response(err, rsp).into()
+}
+
+/// Error handler of JsonConfig, the error is rendered same as Response
+pub fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    extractor_error(err, req, "json_invalid")
+}
+
+/// Error handler of PathConfig
+pub fn path_error_handler(err: PathError, req: &HttpRequest) -> actix_web::Error {
+    extractor_error(err, req, "path_invalid")
+}
+
+/// Error handler of QueryConfig
+pub fn query_error_handler(err: QueryPayloadError, req: &HttpRequest) -> actix_web::Error {
+    extractor_error(err, req, "query_invalid")
+}
+
+/// Error handler of FormConfig
+pub fn form_error_handler(err: UrlencodedError, req: &HttpRequest) -> actix_web::Error {
+    extractor_error(err, req, "form_invalid")
+}
+
+/// Default handler of ErrorHandlers middleware, it renders the error responses which are
+/// not created by Response, such as payload overflow, method mismatch and routing failure
+pub fn error_handler<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let rendered = res
+        .response()
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json") || v.starts_with(PROBLEM_CONTENT_TYPE));
+    if rendered {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+
+    let status = res.status();
+    let message = match res.response().error() {
+        Some(err) => err.to_string(),
+        None => status.canonical_reason().unwrap_or("").to_string(),
+    };
+    let (req, _) = res.into_parts();
+    warn!(
+        "{} {} {} with error: {}",
+        req.method(),
+        req.path(),
+        status.as_u16(),
+        message
+    );
+    let rsp = Response::<NoneBodyData>::new(status, &message, None)
+        .with_error_code(&default_error_code(status))
+        .finished_status_for(&req);
+    Ok(ErrorHandlerResponse::Response(
+        ServiceResponse::new(req, rsp).map_into_right_body(),
+    ))
+}
+
 impl<T: Serialize + Debug> std::fmt::Display for Response<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{{ code: {}, message: {} }}", self.code, self.message)
@@ -132,15 +414,14 @@ impl<T: Serialize + Debug> actix_web::error::ResponseError for Response<T> {
     }
 
     fn error_response(&self) -> HttpResponse {
-        // HttpResponse::build(self.code).json(serde_json::json!(ErrData {
-        //     status: false,
-        //     code: self.code.as_u16(),
-        //     message: self.message.clone(),
-        // }))
-        HttpResponse::Ok().json(serde_json::json!(ErrData {
-            status: false,
-            code: self.code.as_u16(),
-            message: self.message.clone(),
-        }))
+        self.finished()
+    }
+}
+
+impl<T: Serialize + Debug> Responder for Response<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        self.finished_for(req)
     }
 }
@@ -0,0 +1,307 @@
+use crate::access_token::{AccessToken, TokenConfig, TokenError, TokenPermission};
+use crate::error::WebhttpResult;
+use crate::redis::select_redis;
+use crate::refresh::refresh_get;
+use crate::util::now_seconds;
+use crate::websocket::ROOM;
+use actix_http::header::HeaderMap;
+use dashmap::DashMap;
+use fred::prelude::*;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::info;
+
+const REDIS_PREFIX: &str = "webhttp:revoked";
+
+/// redis rejects a too large ttl, the tokens living longer are kept revoked for this time
+const MAX_TTL: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// Keep the later issued-before time of user, the retention is renewed
+const REVOKE_USER_SCRIPT: &str = r#"
+local before = tonumber(redis.call('GET', KEYS[1]) or '0')
+if tonumber(ARGV[1]) > before then
+    before = tonumber(ARGV[1])
+end
+redis.call('SET', KEYS[1], before, 'EX', ARGV[2])
+return before
+"#;
+
+/// Tokens which are invalid before their exp
+///
+/// A token is revoked by its jti, such as logout, and is kept until its exp. A user is
+/// revoked by a timestamp, such as the user is disabled or changes the password, then all
+/// tokens of the user which are issued before it are rejected. Tokens of a refresh family
+/// which is revoked by reuse detection are rejected too. Tokens without jti, such as the
+/// legacy ones, can only be revoked by user.
+pub struct RevocationStore {
+    /// tokens issued before the revocation of user are all expired after it
+    retention: Duration,
+    redis: Option<RedisPool>,
+    /// key is jti, value is exp
+    tokens: DashMap<String, u64>,
+    /// key is user id, value is the issued-before time
+    users: DashMap<u64, u64>,
+}
+
+static REVOCATION: OnceLock<RevocationStore> = OnceLock::<RevocationStore>::new();
+
+pub fn revocation_init(config: &TokenConfig, redis: Option<RedisPool>) -> &'static RevocationStore {
+    REVOCATION.get_or_init(|| {
+        let redis = select_redis(config.revocation_use_redis, redis, "revocation store");
+        let store = RevocationStore {
+            retention: Duration::from_secs(std::cmp::max(
+                config.access_lifetime,
+                config.refresh_lifetime,
+            )),
+            redis,
+            tokens: DashMap::new(),
+            users: DashMap::new(),
+        };
+        if store.redis.is_none() {
+            start_cleaner();
+        }
+        info!(
+            "token revocation is enabled, use redis: {}",
+            store.redis.is_some()
+        );
+        store
+    })
+}
+
+/// return None when the server is not started by webhttp
+pub fn revocation_get() -> Option<&'static RevocationStore> {
+    REVOCATION.get()
+}
+
+/// revoked tokens are released after their exp, and revoked users after the retention
+fn start_cleaner() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            if let Some(store) = revocation_get() {
+                let now = now_seconds();
+                let retention = store.retention.as_secs();
+                store.tokens.retain(|_, exp| *exp > now);
+                store.users.retain(|_, before| *before + retention > now);
+            }
+        }
+    });
+}
+
+impl RevocationStore {
+    fn token_key(jti: &str) -> String {
+        format!("{}:jti:{}", REDIS_PREFIX, jti)
+    }
+
+    fn user_key(user_id: u64) -> String {
+        format!("{}:user:{}", REDIS_PREFIX, user_id)
+    }
+
+    /// Revoke one token until its exp, the empty jti is ignored, it's shared by all legacy
+    /// tokens
+    pub async fn revoke_token(&self, jti: &str, exp: u64) -> WebhttpResult<()> {
+        let now = now_seconds();
+        if jti.is_empty() || exp <= now {
+            return Ok(());
+        }
+        if let Some(redis) = &self.redis {
+            let _: () = redis
+                .set(
+                    Self::token_key(jti),
+                    exp.to_string(),
+                    Some(Expiration::EX(std::cmp::min(exp - now, MAX_TTL) as i64)),
+                    None,
+                    false,
+                )
+                .await?;
+            return Ok(());
+        }
+        self.tokens.insert(jti.to_string(), exp);
+        Ok(())
+    }
+
+    /// Revoke all tokens of user which are issued before now, and close the websocket
+    /// sessions of user. Only the sessions of this instance are closed, the other instances
+    /// reject the tokens of user when they are verified again.
+    pub async fn revoke_user(&self, user_id: u64) -> WebhttpResult<()> {
+        self.revoke_user_before(user_id, now_seconds()).await?;
+        ROOM.close_user_sessions(user_id, "token is revoked");
+        Ok(())
+    }
+
+    /// Revoke the tokens of user whose iat is before the unix time in seconds, the tokens
+    /// which are issued in the same second are kept, such as the login after password change
+    pub async fn revoke_user_before(&self, user_id: u64, issued_before: u64) -> WebhttpResult<()> {
+        if let Some(redis) = &self.redis {
+            let _: u64 = redis
+                .eval(
+                    REVOKE_USER_SCRIPT,
+                    vec![Self::user_key(user_id)],
+                    vec![
+                        issued_before.to_string(),
+                        self.retention.as_secs().to_string(),
+                    ],
+                )
+                .await?;
+            return Ok(());
+        }
+        self.users
+            .entry(user_id)
+            .and_modify(|before| *before = std::cmp::max(*before, issued_before))
+            .or_insert(issued_before);
+        Ok(())
+    }
+
+    pub async fn is_revoked(&self, token: &AccessToken) -> WebhttpResult<bool> {
+        let token_key = if token.jti.is_empty() {
+            None
+        } else {
+            Some(Self::token_key(&token.jti))
+        };
+        let (token_revoked, issued_before) = if let Some(redis) = &self.redis {
+            let values: Vec<Option<String>> = redis
+                .mget(vec![
+                    token_key.clone().unwrap_or_default(),
+                    Self::user_key(token.user_id),
+                ])
+                .await?;
+            (
+                token_key.is_some() && values.first().is_some_and(|v| v.is_some()),
+                values
+                    .get(1)
+                    .and_then(|v| v.as_ref())
+                    .and_then(|v| v.parse::<u64>().ok()),
+            )
+        } else {
+            (
+                token_key.is_some() && self.tokens.contains_key(&token.jti),
+                self.users.get(&token.user_id).map(|v| *v),
+            )
+        };
+        if token_revoked || issued_before.is_some_and(|before| token.iat < before) {
+            return Ok(true);
+        }
+        if let (Some(family), Some(refresh)) = (&token.family, refresh_get()) {
+            return refresh.is_family_revoked(family).await;
+        }
+        Ok(false)
+    }
+
+    /// Err of TokenError::Revoked if the token is revoked
+    pub async fn check(&self, token: &AccessToken) -> WebhttpResult<()> {
+        if self.is_revoked(token).await? {
+            return Err(TokenError::Revoked.into());
+        }
+        Ok(())
+    }
+
+    /// Revoke the token and its refresh family. The token without jti is revoked by user
+    /// with the tokens which are issued not after it.
+    pub async fn logout(&self, token: &AccessToken) -> WebhttpResult<()> {
+        if token.jti.is_empty() {
+            self.revoke_user_before(token.user_id, token.iat.saturating_add(1))
+                .await?;
+        } else {
+            self.revoke_token(&token.jti, token.exp).await?;
+        }
+        if let (Some(family), Some(refresh)) = (&token.family, refresh_get()) {
+            refresh.revoke_family(family).await?;
+        }
+        Ok(())
+    }
+}
+
+/// TokenPermission of the consumer which also rejects the revoked tokens, AppState.token_check
+/// is wrapped by it, so calling check_and_verify directly gets the revocation too
+pub struct RevocationCheck {
+    inner: Arc<dyn TokenPermission + Send + Sync>,
+}
+
+impl RevocationCheck {
+    pub fn wrap(
+        inner: Arc<dyn TokenPermission + Send + Sync>,
+    ) -> Arc<dyn TokenPermission + Send + Sync> {
+        Arc::new(RevocationCheck { inner })
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenPermission for RevocationCheck {
+    async fn check_and_verify(&self, req: (HeaderMap, String)) -> WebhttpResult<AccessToken> {
+        let token = self.inner.check_and_verify(req).await?;
+        if let Some(store) = revocation_get() {
+            store.check(&token).await?;
+        }
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn store() -> RevocationStore {
+        RevocationStore {
+            retention: Duration::from_secs(60),
+            redis: None,
+            tokens: DashMap::new(),
+            users: DashMap::new(),
+        }
+    }
+
+    fn token(user_id: u64, jti: &str, iat: u64) -> AccessToken {
+        let mut token =
+            AccessToken::new(user_id, "account", "name", "app", Duration::from_secs(60));
+        token.jti = jti.to_string();
+        token.iat = iat;
+        token
+    }
+
+    #[test]
+    fn logout_revokes_only_the_token() {
+        let store = store();
+        let now = now_seconds();
+        block_on(async {
+            store.logout(&token(1, "a", now)).await.unwrap();
+            assert!(store.is_revoked(&token(1, "a", now)).await.unwrap());
+            assert!(!store.is_revoked(&token(1, "b", now)).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn logout_of_legacy_token_keeps_other_users() {
+        let store = store();
+        let now = now_seconds();
+        block_on(async {
+            store.logout(&token(1, "", 0)).await.unwrap();
+            assert!(store.is_revoked(&token(1, "", 0)).await.unwrap());
+            // legacy tokens of other users and the new tokens of the user are kept
+            assert!(!store.is_revoked(&token(2, "", 0)).await.unwrap());
+            assert!(!store.is_revoked(&token(1, "a", now)).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn empty_jti_is_never_revoked_by_jti() {
+        let store = store();
+        block_on(async {
+            store.revoke_token("", now_seconds() + 60).await.unwrap();
+            assert!(store.tokens.is_empty());
+        });
+    }
+
+    #[test]
+    fn revoke_user_keeps_login_of_same_second() {
+        let store = store();
+        let now = now_seconds();
+        block_on(async {
+            store.revoke_user_before(1, now).await.unwrap();
+            assert!(store.is_revoked(&token(1, "a", now - 1)).await.unwrap());
+            assert!(!store.is_revoked(&token(1, "b", now)).await.unwrap());
+            // the earlier revocation doesn't move the time back
+            store.revoke_user_before(1, now - 10).await.unwrap();
+            assert!(store.is_revoked(&token(1, "a", now - 1)).await.unwrap());
+        });
+    }
+}
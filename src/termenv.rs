@@ -1,5 +1,7 @@
+use crate::client::{ApiError, ResponseExt};
 use dashmap::DashMap;
 use reqwest::Client;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 use std::path::PathBuf;
 use std::sync::OnceLock;
@@ -54,6 +56,38 @@ pub fn termenv_init(server: String, urlprefix: String, cachedir: &str) -> &'stat
     })
 }
 
+impl Termenv {
+    /// full url of the api path, such as /user/list
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}{}", self.server, self.urlprefix, path)
+    }
+
+    /// GET the api and decode the Response envelope
+    pub async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, ApiError> {
+        self.client
+            .get(self.url(path))
+            .send()
+            .await?
+            .envelope()
+            .await
+    }
+
+    /// POST json body to the api and decode the Response envelope
+    pub async fn post_json<T: DeserializeOwned, B: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<T, ApiError> {
+        self.client
+            .post(self.url(path))
+            .json(body)
+            .send()
+            .await?
+            .envelope()
+            .await
+    }
+}
+
 pub fn termenv_get() -> &'static Termenv {
     TERMENV.get().unwrap()
 }
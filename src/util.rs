@@ -0,0 +1,46 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// seconds since the unix epoch
+pub(crate) fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// milliseconds since the unix epoch
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// split a comma separated list, the blank items are dropped
+pub(crate) fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_list_drops_blank_items() {
+        assert_eq!(split_list(" admin, ,user,"), vec!["admin", "user"]);
+        assert!(split_list("").is_empty());
+    }
+
+    #[test]
+    fn now_is_consistent() {
+        let seconds = now_seconds();
+        let millis = now_millis();
+        assert!(millis / 1000 >= seconds);
+        assert!(millis / 1000 - seconds <= 1);
+    }
+}
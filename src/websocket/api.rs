@@ -1,4 +1,13 @@
-use super::{super::AppState, wsconn::WsConn};
+use super::{
+    super::client_ip::client_ip, super::AppState, resume::resume_get, room::ROOM, wsconn::WsConn,
+};
+use crate::access_token::TokenError;
+use crate::auth::{token_and_path, verify_token};
+use crate::auth_cookie::request_token;
+use crate::cors::{is_same_origin, origin_allowed};
+use crate::error::WebhttpError;
+use crate::response::{NoneBodyData, Response};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
 use actix_web::{get, web, HttpRequest, HttpResponse, Responder, Scope};
 use actix_web_actors::ws;
 use time::macros::offset;
@@ -23,17 +32,78 @@ pub async fn ws_entry(
 ) -> HttpResponse {
     // info!("req: {:?} params: {:?}", req, params);
     let (business, actor, connid) = params.into_inner();
-    let ip = req.peer_addr().unwrap().to_string();
+    let ip = match client_ip(&req) {
+        Some(ip) => ip.to_string(),
+        None => "-".to_string(),
+    };
 
     let headers = req.headers();
-    let token = if headers.contains_key("token") {
-        headers.get("token").unwrap().to_str().unwrap().to_string()
-    } else {
-        warn!("@@@@@ token is null for this websocket connection @@@@@");
-        "".to_string()
+    // browsers send Origin in upgrade request, but cors is not applied to websocket
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        let origin = origin.to_str().unwrap_or_default();
+        if !origin_allowed(req.path(), origin) && !is_same_origin(req.head(), origin) {
+            warn!("websocket origin is not allowed: {}", origin);
+            return Response::<NoneBodyData>::forbidden("origin is not allowed")
+                .with_error_code("origin_not_allowed")
+                .finished_status_for(&req);
+        }
+    }
+
+    let token = match request_token(&req) {
+        Some(token) => token,
+        None => {
+            warn!("@@@@@ token is null for this websocket connection @@@@@");
+            "".to_string()
+        }
     };
 
-    let wsconn = WsConn::new(
+    // revoked token is rejected, other tokens are still checked by the consumer
+    let mut user_id = None;
+    if !token.is_empty() {
+        match verify_token(appdata.token_check.clone(), token_and_path(&req)).await {
+            Ok(verified) => user_id = Some(verified.user_id),
+            Err(WebhttpError::Token(TokenError::Revoked)) => {
+                warn!("websocket token is revoked: {}_{}", actor, connid);
+                return Response::<NoneBodyData>::unauthorized("token is revoked")
+                    .with_error_code("token_revoked")
+                    .finished_status_for(&req);
+            }
+            Err(e) => {
+                debug!("verify websocket token with error: {:?}", e);
+            }
+        }
+    }
+
+    // resumable session: resume-token and resume-seq are given by reconnecting client
+    let mut resume_token = None;
+    let mut resume_seq = None;
+    if let Some(store) = resume_get() {
+        let session_id = format!("{}_{}", actor, connid);
+        let client_token = headers
+            .get("resume-token")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let client_seq = headers
+            .get("resume-seq")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        // don't touch the buffer of a live session, this connection will be rejected
+        if !ROOM.sessions.contains_key(&session_id) {
+            match store.open(&session_id, client_token.as_deref()).await {
+                Ok((token, resumed)) => {
+                    if resumed {
+                        resume_seq = Some(client_seq.unwrap_or(0));
+                    }
+                    resume_token = Some(token);
+                }
+                Err(e) => {
+                    warn!("open resume buffer with error: {:?}", e);
+                }
+            }
+        }
+    }
+
+    let mut wsconn = WsConn::new(
         ip,
         business,
         connid,
@@ -41,13 +111,21 @@ pub async fn ws_entry(
         token,
         appdata.get_ref().clone(),
     );
+    wsconn.resume_seq = resume_seq;
+    wsconn.user_id = user_id;
     // let resp = ws::start(wsconn, &req, stream);
     let resp = ws::WsResponseBuilder::new(wsconn, &req, stream)
         .frame_size(1024 * 1024 * 64)
         .start();
 
     debug!("{:?}", resp);
-    if let Ok(resp) = resp {
+    if let Ok(mut resp) = resp {
+        if let Some(resume_token) = resume_token {
+            if let Ok(value) = HeaderValue::from_str(&resume_token) {
+                resp.headers_mut()
+                    .insert(HeaderName::from_static("resume-token"), value);
+            }
+        }
         resp
     } else {
         HttpResponse::BadRequest()
@@ -0,0 +1,140 @@
+use super::msg::OutMessage;
+use super::room::ROOM;
+use crate::error::WebhttpResult;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+/// Prefix of control frame, it makes control frame different from webproto message
+pub const CONTROL_MAGIC: &[u8] = b"\x00whc";
+
+/// Frames which are handled by webhttp itself and never passed to ServiceCallback
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Control {
+    /// server -> client, indication which should be acked, the client must drop
+    /// the duplicated id because it will be resent until acked
+    Reliable {
+        id: String,
+        attempt: u32,
+        data: Vec<u8>,
+    },
+    /// client -> server, acknowledgement of reliable indication
+    Ack { id: String },
+    /// server -> client, the client can send more `credits` items of the stream
+    StreamCredit { event_id: String, credits: u32 },
+    /// server -> client, the command or stream is not needed anymore
+    Cancel { event_id: String },
+    /// client -> server, the stream is finished after `count` items
+    StreamEnd { event_id: String, count: u64 },
+    /// client -> server, the stream is failed after `count` items
+    StreamError {
+        event_id: String,
+        count: u64,
+        message: String,
+    },
+    /// server -> client, the session is closed by server, such as its token is revoked
+    Close { reason: String },
+}
+
+impl Control {
+    pub fn encode(&self) -> WebhttpResult<Vec<u8>> {
+        let mut data = CONTROL_MAGIC.to_vec();
+        data.extend(serde_json::to_vec(self)?);
+        Ok(data)
+    }
+
+    /// return None if the data is not a control frame
+    pub fn decode(data: &[u8]) -> Option<Control> {
+        let body = data.strip_prefix(CONTROL_MAGIC)?;
+        match serde_json::from_slice::<Control>(body) {
+            Ok(control) => Some(control),
+            Err(e) => {
+                debug!("decode control frame with error: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Send control frame to the session without waiting, return false if it's not sent
+pub fn send_control(session_id: &str, control: &Control) -> bool {
+    let addr = match ROOM.sessions.get(session_id) {
+        Some(entry) => entry.1.clone(),
+        None => return false,
+    };
+    match control.encode() {
+        Ok(data) => addr.do_send(OutMessage { data, seq: None }),
+        Err(e) => {
+            debug!("encode control frame with error: {:?}", e);
+            return false;
+        }
+    }
+    true
+}
+
+lazy_static! {
+    /// key is the session id of client and the id of reliable indication, value is notified
+    /// when acked
+    pub(crate) static ref ACK_WAITERS: DashMap<(String, String), oneshot::Sender<()>> =
+        DashMap::new();
+}
+
+/// Called when the client acks a reliable indication, duplicated acks and acks of the
+/// indications sent to other sessions are ignored
+pub fn acknowledge(session_id: &str, id: &str) {
+    if let Some((_, waiter)) = ACK_WAITERS.remove(&(session_id.to_string(), id.to_string())) {
+        let _ = waiter.send(());
+    } else {
+        debug!(
+            "ack for unknown or finished indication: {} of {}",
+            id, session_id
+        );
+    }
+}
+
+/// Terminal frame of stream, `count` is the item number sent by client before it
+#[derive(Clone, Debug)]
+pub enum StreamSignal {
+    End { count: u64 },
+    Error { count: Option<u64>, message: String },
+}
+
+lazy_static! {
+    /// key is the event_id of stream, value.0 is the session id of client
+    pub(crate) static ref STREAM_SIGNALS: DashMap<String, (String, mpsc::UnboundedSender<StreamSignal>)> =
+        DashMap::new();
+}
+
+/// Called when the client sends a terminal frame of stream, only the session of stream
+/// can finish it
+pub fn stream_signal(session_id: &str, event_id: &str, signal: StreamSignal) {
+    match STREAM_SIGNALS.get(event_id) {
+        Some(entry) if entry.0.eq(session_id) => {
+            let _ = entry.1.send(signal);
+        }
+        Some(_) => {
+            warn!(
+                "signal for stream {} of another session from {}",
+                event_id, session_id
+            );
+        }
+        None => {
+            debug!("signal for unknown or finished stream: {}", event_id);
+        }
+    }
+}
+
+/// Fail all streams of the session when it's disconnected
+pub fn close_streams(session_id: &str) {
+    for entry in STREAM_SIGNALS.iter() {
+        if entry.value().0.eq(session_id) {
+            let _ = entry.value().1.send(StreamSignal::Error {
+                count: None,
+                message: "client disconnected".into(),
+            });
+        }
+    }
+}
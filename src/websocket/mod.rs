@@ -1,11 +1,15 @@
 pub mod api;
+pub mod control;
 pub mod msg;
+pub mod resume;
 pub mod room;
 pub mod worker;
 pub mod wsconn;
 
 pub use api::*;
+pub use control::*;
 pub use msg::*;
+pub use resume::*;
 pub use room::*;
 pub use worker::*;
 pub use wsconn::*;
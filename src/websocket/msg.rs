@@ -23,6 +23,7 @@ pub enum ActorMsg {
 #[rtype(result = "()")]
 pub struct OutMessage {
     pub data: Vec<u8>,
+    pub seq: Option<u64>, // indication seq of resumable session
 }
 
 // #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -48,6 +49,8 @@ pub struct ConnInfo {
     pub connid: String,
     pub actor: String,
     pub token: String,
+    #[serde(default)]
+    pub user_id: Option<u64>, // user of the verified token
 }
 
 #[derive(prelude::Message, Clone)]
@@ -0,0 +1,297 @@
+use crate::error::WebhttpResult;
+use crate::options::ResumeConfig;
+use crate::redis::select_redis;
+use dashmap::DashMap;
+use fred::prelude::*;
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+const REDIS_PREFIX: &str = "webhttp:resume";
+
+/// Keys of an attached session live for this longer than the grace, and are refreshed by
+/// heartbeat, so they are only released after the session is dropped
+const ATTACHED_TTL_MARGIN: Duration = Duration::from_secs(30);
+
+/// Take the next seq and buffer the indication in one step, so the buffered indications are
+/// always in seq order. The entry is `{seq}:{data}`, return nil if the session is not opened
+const PUSH_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return false
+end
+local seq = redis.call('INCR', KEYS[2])
+redis.call('RPUSH', KEYS[3], seq .. ':' .. ARGV[1])
+redis.call('LTRIM', KEYS[3], -tonumber(ARGV[2]), -1)
+return seq
+"#;
+
+static RESUME: OnceLock<ResumeStore> = OnceLock::<ResumeStore>::new();
+
+/// Buffer of one session, the seq of indication starts from 1 and only counts indications
+struct ResumeBuffer {
+    token: String,
+    last_seq: u64,
+    messages: VecDeque<(u64, Vec<u8>)>,
+    detached_at: Option<Instant>,
+}
+
+/// Keep recent outbound indications of each session for reconnecting clients
+///
+/// The client gets `resume-token` in the header of websocket handshake response, and counts
+/// the received indications. After reconnecting with the `resume-token` and `resume-seq`
+/// headers, all buffered indications after `resume-seq` are replayed in order. If the
+/// returned token is different from the old one, the session is a new one and the client
+/// should reset its counter to 0.
+pub struct ResumeStore {
+    pub config: ResumeConfig,
+    redis: Option<RedisPool>,
+    buffers: DashMap<String, ResumeBuffer>,
+}
+
+pub fn resume_init(config: ResumeConfig, redis: Option<RedisPool>) -> &'static ResumeStore {
+    RESUME.get_or_init(|| {
+        let redis = select_redis(config.use_redis, redis, "resume store");
+        let store = ResumeStore {
+            config,
+            redis,
+            buffers: DashMap::new(),
+        };
+        if store.redis.is_none() {
+            start_cleaner(store.config.grace_seconds);
+        }
+        info!("websocket session resume is enabled: {:?}", store.config);
+        store
+    })
+}
+
+/// return None when session resume is not enabled
+pub fn resume_get() -> Option<&'static ResumeStore> {
+    RESUME.get()
+}
+
+fn start_cleaner(grace_seconds: u64) {
+    let grace = Duration::from_secs(grace_seconds);
+    let interval = Duration::from_secs(std::cmp::max(1, grace_seconds / 2));
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Some(store) = resume_get() {
+                store
+                    .buffers
+                    .retain(|session_id, buffer| match buffer.detached_at {
+                        Some(detached_at) if detached_at.elapsed() > grace => {
+                            debug!("release resume buffer: {}", session_id);
+                            false
+                        }
+                        _ => true,
+                    });
+            }
+        }
+    });
+}
+
+impl ResumeStore {
+    fn token_key(session_id: &str) -> String {
+        format!("{}:{}:token", REDIS_PREFIX, session_id)
+    }
+
+    fn seq_key(session_id: &str) -> String {
+        format!("{}:{}:seq", REDIS_PREFIX, session_id)
+    }
+
+    fn msgs_key(session_id: &str) -> String {
+        format!("{}:{}:msgs", REDIS_PREFIX, session_id)
+    }
+
+    /// Open the buffer for a connecting session, return the token and whether it is resumed
+    pub async fn open(
+        &self,
+        session_id: &str,
+        client_token: Option<&str>,
+    ) -> WebhttpResult<(String, bool)> {
+        if let Some(redis) = &self.redis {
+            let token_key = Self::token_key(session_id);
+            let stored: Option<String> = redis.get(&token_key).await?;
+            if let (Some(stored), Some(client_token)) = (&stored, client_token) {
+                if stored.eq(client_token) {
+                    self.refresh_redis(redis, session_id, self.attached_ttl())
+                        .await?;
+                    return Ok((stored.clone(), true));
+                }
+            }
+
+            let token = uuid::Uuid::new_v4().to_string();
+            let _: () = redis
+                .del(vec![Self::seq_key(session_id), Self::msgs_key(session_id)])
+                .await?;
+            let _: () = redis
+                .set(
+                    &token_key,
+                    token.clone(),
+                    Some(Expiration::EX(self.attached_ttl())),
+                    None,
+                    false,
+                )
+                .await?;
+            return Ok((token, false));
+        }
+
+        if let Some(client_token) = client_token {
+            if let Some(mut buffer) = self.buffers.get_mut(session_id) {
+                if buffer.token.eq(client_token) {
+                    buffer.detached_at = None;
+                    return Ok((buffer.token.clone(), true));
+                }
+            }
+        }
+
+        let token = uuid::Uuid::new_v4().to_string();
+        self.buffers.insert(
+            session_id.to_string(),
+            ResumeBuffer {
+                token: token.clone(),
+                last_seq: 0,
+                messages: VecDeque::new(),
+                detached_at: None,
+            },
+        );
+        Ok((token, false))
+    }
+
+    /// Buffer an outbound indication, return the seq if the session has a buffer
+    pub async fn push(&self, session_id: &str, data: &[u8]) -> WebhttpResult<Option<u64>> {
+        if let Some(redis) = &self.redis {
+            let seq: Option<u64> = redis
+                .eval(
+                    PUSH_SCRIPT,
+                    vec![
+                        Self::token_key(session_id),
+                        Self::seq_key(session_id),
+                        Self::msgs_key(session_id),
+                    ],
+                    vec![
+                        RedisValue::Bytes(data.to_vec().into()),
+                        RedisValue::Integer(std::cmp::max(1, self.config.max_buffered) as i64),
+                    ],
+                )
+                .await?;
+            return Ok(seq);
+        }
+
+        if let Some(mut buffer) = self.buffers.get_mut(session_id) {
+            buffer.last_seq += 1;
+            let seq = buffer.last_seq;
+            buffer.messages.push_back((seq, data.to_vec()));
+            while buffer.messages.len() > self.config.max_buffered {
+                buffer.messages.pop_front();
+            }
+            return Ok(Some(seq));
+        }
+        Ok(None)
+    }
+
+    /// Get the buffered indications after the seq which is received by client
+    pub async fn replay(
+        &self,
+        session_id: &str,
+        after_seq: u64,
+    ) -> WebhttpResult<Vec<(u64, Vec<u8>)>> {
+        let messages = if let Some(redis) = &self.redis {
+            let values: Vec<RedisValue> = redis.lrange(Self::msgs_key(session_id), 0, -1).await?;
+            values
+                .iter()
+                .filter_map(|value| value.as_bytes())
+                .filter_map(|entry| {
+                    let split = entry.iter().position(|v| *v == b':')?;
+                    let seq = std::str::from_utf8(&entry[..split]).ok()?.parse().ok()?;
+                    Some((seq, entry[split + 1..].to_vec()))
+                })
+                .collect::<Vec<(u64, Vec<u8>)>>()
+        } else {
+            match self.buffers.get(session_id) {
+                Some(buffer) => buffer.messages.iter().cloned().collect(),
+                None => Vec::new(),
+            }
+        };
+
+        if let Some((first_seq, _)) = messages.first() {
+            if *first_seq > after_seq + 1 {
+                warn!(
+                    "resume buffer of {} is overflowed, indications {}-{} are lost",
+                    session_id,
+                    after_seq + 1,
+                    first_seq - 1
+                );
+            }
+        }
+        Ok(messages
+            .into_iter()
+            .filter(|(seq, _)| *seq > after_seq)
+            .collect())
+    }
+
+    /// The session is connected again, stop the grace countdown
+    pub fn attach(&self, session_id: &str) {
+        if self.redis.is_some() {
+            self.spawn_refresh(session_id, self.attached_ttl());
+            return;
+        }
+        if let Some(mut buffer) = self.buffers.get_mut(session_id) {
+            buffer.detached_at = None;
+        }
+    }
+
+    /// Called by heartbeat of the attached session, so its buffer is not released in redis
+    pub fn keepalive(&self, session_id: &str) {
+        if self.redis.is_some() {
+            self.spawn_refresh(session_id, self.attached_ttl());
+        }
+    }
+
+    /// The session is dropped, start the grace countdown
+    pub fn detach(&self, session_id: &str) {
+        if self.redis.is_some() {
+            self.spawn_refresh(session_id, self.config.grace_seconds as i64);
+            return;
+        }
+        if let Some(mut buffer) = self.buffers.get_mut(session_id) {
+            buffer.detached_at = Some(Instant::now());
+        }
+    }
+
+    fn attached_ttl(&self) -> i64 {
+        (self.config.grace_seconds + ATTACHED_TTL_MARGIN.as_secs()) as i64
+    }
+
+    fn spawn_refresh(&self, session_id: &str, ttl: i64) {
+        let redis = match &self.redis {
+            Some(redis) => redis.clone(),
+            None => return,
+        };
+        let session_id = session_id.to_string();
+        actix_rt::spawn(async move {
+            if let Some(store) = resume_get() {
+                if let Err(e) = store.refresh_redis(&redis, &session_id, ttl).await {
+                    warn!("refresh resume buffer with error: {:?}", e);
+                }
+            }
+        });
+    }
+
+    async fn refresh_redis(
+        &self,
+        redis: &RedisPool,
+        session_id: &str,
+        ttl: i64,
+    ) -> WebhttpResult<()> {
+        for key in [
+            Self::token_key(session_id),
+            Self::seq_key(session_id),
+            Self::msgs_key(session_id),
+        ] {
+            let _: () = redis.expire(key, ttl).await?;
+        }
+        Ok(())
+    }
+}
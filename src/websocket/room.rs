@@ -1,4 +1,6 @@
+use super::control::Control;
 use super::msg::{ActorMsg, ConnInfo, Connect, Disconnect, OutMessage};
+use crate::error::{WebhttpError, WebhttpResult};
 use actix::prelude::Recipient;
 use dashmap::{DashMap, DashSet};
 use lazy_static::lazy_static;
@@ -64,15 +66,40 @@ impl Room {
         conn_addr_list
     }
 
-    pub fn add(&self, data: &Connect) -> anyhow::Result<ActorMsg> {
+    /// Close the sessions of user, the client gets a close control frame with the reason.
+    /// Return the number of closed sessions.
+    pub fn close_user_sessions(&self, user_id: u64, reason: &str) -> usize {
+        let close = match (Control::Close {
+            reason: reason.to_string(),
+        })
+        .encode()
+        {
+            Ok(data) => data,
+            Err(_) => return 0,
+        };
+        let mut closed = 0;
+        for each in self.sessions.iter() {
+            if each.value().0.user_id == Some(user_id) {
+                each.value().1.do_send(OutMessage {
+                    data: close.clone(),
+                    seq: None,
+                });
+                closed += 1;
+            }
+        }
+        if closed > 0 {
+            info!("close {} sessions of user {}: {}", closed, user_id, reason);
+        }
+        closed
+    }
+
+    pub fn add(&self, data: &Connect) -> WebhttpResult<ActorMsg> {
         // let id_to = format!("{}_{}", data.conn.actor, data.conn.connid);
         let id_to = data.conn.get_session_id();
         info!("ws connect info: {:?}", id_to);
         if self.sessions.contains_key(&id_to) {
             // if connid_actor aleady in sessions, return error
-            return Err(anyhow::anyhow!(
-                "already have this connid_actor name in sessions"
-            ));
+            return Err(WebhttpError::SessionExisted { session_id: id_to });
         }
 
         self.sessions.entry(id_to.clone()).or_insert_with(|| {
@@ -84,10 +111,10 @@ impl Room {
             // self.mutexes.entry(id_to.clone()).or_insert(false);
             (data.conn.clone(), data.addr.clone())
         });
-        return anyhow::Ok(ActorMsg::Ok);
+        return Ok(ActorMsg::Ok);
     }
 
-    pub fn remove(&self, data: &Disconnect) -> anyhow::Result<ActorMsg> {
+    pub fn remove(&self, data: &Disconnect) -> WebhttpResult<ActorMsg> {
         // let id_to = format!("{}_{}", data.conn.actor, data.conn.connid);
         let id_to = data.conn.get_session_id();
         // if self.mutexes.remove(&id_to).is_some() {
@@ -112,7 +139,7 @@ impl Room {
                 self.rooms.remove(&data.conn.connid);
             }
         }
-        return anyhow::Ok(ActorMsg::Ok);
+        return Ok(ActorMsg::Ok);
     }
 }
 
@@ -36,6 +36,9 @@ impl Handler<Connect> for Worker {
             match actor_msg {
                 ActorMsg::Ok => {
                     super::ROOM.add(&msg)?;
+                    if let Some(store) = super::resume_get() {
+                        store.attach(&msg.conn.get_session_id());
+                    }
                 }
                 _ => {}
             }
@@ -53,6 +56,10 @@ impl Handler<Disconnect> for Worker {
     type Result = anyhow::Result<ActorMsg>;
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) -> Self::Result {
         super::ROOM.remove(&msg)?;
+        if let Some(store) = super::resume_get() {
+            store.detach(&msg.conn.get_session_id());
+        }
+        super::close_streams(&msg.conn.get_session_id());
         self.consumer
             .wsdata(WsData::WsDisconnect { data: msg }, self.consumer.clone())
         // futures::executor::block_on(async {
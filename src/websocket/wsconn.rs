@@ -1,6 +1,9 @@
 use super::super::AppState;
+use super::control::{acknowledge, stream_signal, Control, StreamSignal};
 use super::msg::{ActorMsg, ConnInfo, Connect, Disconnect, InMessage, OutMessage};
-use tracing::{error, trace, warn};
+use super::resume::resume_get;
+use crate::rate_limit::{rate_limit_get, TokenBucket};
+use tracing::{debug, error, trace, warn};
 
 use actix::{fut, ActorContext, ActorFutureExt, ContextFutureSpawner, WrapFuture};
 use actix::{Actor, Running, StreamHandler};
@@ -16,12 +19,16 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(20);
 
 pub struct WsConn {
     pub hb: Instant,
-    pub ip: String,       // client IP
-    pub business: String, // business name
-    pub connid: String,   // client ID, can be roome name
-    pub actor: String,    // role name
-    pub token: String,    // token info
+    pub ip: String,           // client IP
+    pub business: String,     // business name
+    pub connid: String,       // client ID, can be roome name
+    pub actor: String,        // role name
+    pub token: String,        // token info
+    pub user_id: Option<u64>, // user of the verified token
     pub state: AppState,
+    pub resume_seq: Option<u64>, // last indication seq received by client when resuming
+    pub sent_seq: u64,           // last indication seq sent to client
+    pub limiter: Option<TokenBucket>, // inbound message rate of this session
 
     pub in_room: Arc<Mutex<bool>>,
     pub exit_lock: Arc<Mutex<Option<Vec<u8>>>>,
@@ -43,7 +50,13 @@ impl WsConn {
             connid: connid,
             actor: actor,
             token: token,
+            user_id: None,
             state: state,
+            resume_seq: None,
+            sent_seq: 0,
+            limiter: rate_limit_get()
+                .and_then(|v| v.config.websocket.as_ref())
+                .map(|v| TokenBucket::new(&v.quota)),
             in_room: Arc::new(Mutex::new(false)),
             exit_lock: Arc::new(Mutex::new(None)),
         }
@@ -56,6 +69,7 @@ impl WsConn {
             connid: self.connid.clone(),
             actor: self.actor.clone(),
             token: self.token.clone(),
+            user_id: self.user_id,
         }
     }
 }
@@ -86,12 +100,13 @@ impl Actor for WsConn {
                 state: self.state.clone(),
             })
             .into_actor(self)
-            .then(move |res, _conn, ctx| {
+            .then(move |res, conn, ctx| {
                 match res {
                     Ok(_res) => match _res {
                         Ok(_msg) => match _msg {
                             ActorMsg::Ok => {
                                 *in_room_copied.lock().unwrap() = true;
+                                conn.replay(ctx);
                             }
                             _ => {}
                         },
@@ -134,6 +149,90 @@ impl Actor for WsConn {
 }
 
 impl WsConn {
+    /// send the buffered indications which are missed by the resumed client
+    fn replay(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let (store, after_seq) = match (resume_get(), self.resume_seq) {
+            (Some(store), Some(after_seq)) => (store, after_seq),
+            _ => return,
+        };
+        self.sent_seq = after_seq;
+        let session_id = self.get_conn_info().get_session_id();
+        // wait for replaying, the live indications are handled after it
+        async move { store.replay(&session_id, after_seq).await }
+            .into_actor(self)
+            .then(|res, conn, ctx| {
+                match res {
+                    Ok(messages) => {
+                        debug!("replay {} indications to client", messages.len());
+                        for (seq, data) in messages {
+                            if seq > conn.sent_seq {
+                                ctx.binary(data);
+                                conn.sent_seq = seq;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("replay indications with error: {:?}", e);
+                    }
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// false if the message should be dropped
+    fn allow_message(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let limiter = match self.limiter.as_mut() {
+            Some(limiter) => limiter,
+            None => return true,
+        };
+        if limiter.take().allowed {
+            return true;
+        }
+        warn!(
+            "websocket message is rate limited: {}",
+            self.get_conn_info().get_session_id()
+        );
+        let close_on_exceed = rate_limit_get()
+            .and_then(|v| v.config.websocket.as_ref())
+            .is_some_and(|v| v.close_on_exceed);
+        if close_on_exceed {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("rate limited".into()),
+            }));
+            ctx.stop();
+        }
+        false
+    }
+
+    fn handle_control(&mut self, control: Control, _ctx: &mut ws::WebsocketContext<Self>) {
+        let session_id = self.get_conn_info().get_session_id();
+        match control {
+            Control::Ack { id } => acknowledge(&session_id, &id),
+            Control::StreamEnd { event_id, count } => {
+                stream_signal(&session_id, &event_id, StreamSignal::End { count });
+            }
+            Control::StreamError {
+                event_id,
+                count,
+                message,
+            } => {
+                stream_signal(
+                    &session_id,
+                    &event_id,
+                    StreamSignal::Error {
+                        count: Some(count),
+                        message,
+                    },
+                );
+            }
+            _ => {
+                warn!("unexpected control frame from client: {:?}", control);
+            }
+        }
+    }
+
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
@@ -143,6 +242,11 @@ impl WsConn {
             }
             trace!("sending ping info");
             ctx.ping(b"ping");
+            if *act.in_room.lock().unwrap() {
+                if let Some(store) = resume_get() {
+                    store.keepalive(&act.get_conn_info().get_session_id());
+                }
+            }
         });
     }
 }
@@ -166,6 +270,15 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
             }
             Ok(ws::Message::Nop) => (),
             Ok(ws::Message::Binary(bin)) => {
+                // control frames are also limited, then they can't flood the session
+                if !self.allow_message(ctx) {
+                    return;
+                }
+                // control frame is handled by webhttp, and not passed to worker
+                if let Some(control) = Control::decode(&bin) {
+                    self.handle_control(control, ctx);
+                    return;
+                }
                 // info!("binary msg");
                 // send message info to one random actor worker
                 let worker = self
@@ -241,6 +354,24 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
 impl Handler<OutMessage> for WsConn {
     type Result = ();
     fn handle(&mut self, msg: OutMessage, ctx: &mut Self::Context) {
+        if let Some(seq) = msg.seq {
+            // already sent by replaying
+            if seq <= self.sent_seq {
+                return;
+            }
+            self.sent_seq = seq;
+        }
+        let close = match Control::decode(&msg.data) {
+            Some(Control::Close { reason }) => Some(reason),
+            _ => None,
+        };
         ctx.binary(msg.data);
+        if let Some(reason) = close {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some(reason),
+            }));
+            ctx.stop();
+        }
     }
 }